@@ -2,9 +2,14 @@
 extern crate forth;
 extern crate test;
 
-use forth::{Forth};
+use forth::{Forth, ForthResult, kernels::Kernel};
 use test::Bencher;
 
+// the input has to outlive the forth environment, so formatted input is handed over as an owned stream
+fn evaluate<K: Kernel>(f: &mut Forth<K>, input: String) -> ForthResult {
+    f.evaluate_stream(input.chars().collect::<Vec<_>>().into_iter())
+}
+
 // literal: sequential accesses and reuse accesses
 #[bench]
 fn embedded_literal_sequential_test(b: &mut Bencher) {
@@ -12,12 +17,12 @@ fn embedded_literal_sequential_test(b: &mut Bencher) {
     b.iter(move || {
         // create literals
         for i in 0..800 {
-            assert!(f.evaluate_string(&format!("{} : test{} literal . ;", i, i)).is_ok());
+            assert!(evaluate(&mut f, format!("{} : test{} literal . ;", i, i)).is_ok());
         }
 
         for _ in 0..100 {
             for i in 0..800 {
-                assert!(f.evaluate_string(&format!("test{}", i)).is_ok());
+                assert!(evaluate(&mut f, format!("test{}", i)).is_ok());
             }    
         }
     });
@@ -29,12 +34,12 @@ fn embedded_literal_reuse_test(b: &mut Bencher) {
     b.iter(move || {
         // create literals
         for i in 0..800 {
-            assert!(f.evaluate_string(&format!("{} : test{} literal . ;", i, i)).is_ok());
+            assert!(evaluate(&mut f, format!("{} : test{} literal . ;", i, i)).is_ok());
         }
 
         for i in 0..800 {
             for _ in 0..100 {
-                assert!(f.evaluate_string(&format!("test{}", i)).is_ok());
+                assert!(evaluate(&mut f, format!("test{}", i)).is_ok());
             }    
         }
     });
@@ -46,12 +51,12 @@ fn compiled_literal_sequential_test(b: &mut Bencher) {
     b.iter(move || {
         // create literals
         for i in 0..800 {
-            assert!(f.evaluate_string(&format!("{} : test{} _literal . ;", i, i)).is_ok());
+            assert!(evaluate(&mut f, format!("{} : test{} _literal . ;", i, i)).is_ok());
         }
 
         for _ in 0..100 {
             for i in 0..800 {
-                assert!(f.evaluate_string(&format!("test{}", i)).is_ok());
+                assert!(evaluate(&mut f, format!("test{}", i)).is_ok());
             }    
         }
     });
@@ -63,13 +68,43 @@ fn compiled_literal_reuse_test(b: &mut Bencher) {
     b.iter(move || {
         // create literals
         for i in 0..800 {
-            assert!(f.evaluate_string(&format!("{} : test{} _literal . ;", i, i)).is_ok());
+            assert!(evaluate(&mut f, format!("{} : test{} _literal . ;", i, i)).is_ok());
         }
 
         for i in 0..800 {
             for _ in 0..100 {
-                assert!(f.evaluate_string(&format!("test{}", i)).is_ok());
+                assert!(evaluate(&mut f, format!("test{}", i)).is_ok());
             }    
         }
     });
 }
+
+// compiled instructions: a branch executed on every iteration of a tight loop
+#[bench]
+fn compiled_branch_loop_test(b: &mut Bencher) {
+    let mut f = Forth::default();
+    assert!(f.evaluate_string(": countdown begin 1 - dup 0= until drop ;").is_ok());
+    b.iter(|| {
+        assert!(f.evaluate_string("100000 countdown").is_ok());
+    });
+}
+
+// memory: a variable read and written on every iteration of a tight loop
+#[bench]
+fn variable_loop_test(b: &mut Bencher) {
+    let mut f = Forth::default();
+    assert!(f.evaluate_string("variable x : bump begin x @ 1 + x ! 1 - dup 0= until drop ;").is_ok());
+    b.iter(|| {
+        assert!(f.evaluate_string("100000 bump").is_ok());
+    });
+}
+
+// memory: bulk copies and fills of a buffer in data space
+#[bench]
+fn move_fill_test(b: &mut Bencher) {
+    let mut f = Forth::default();
+    assert!(f.evaluate_string("here 4096 allot constant buffer").is_ok());
+    b.iter(|| {
+        assert!(f.evaluate_string("buffer 2048 65 fill  buffer buffer 2048 + 2048 move  buffer 1 + buffer 4095 cmove").is_ok());
+    });
+}
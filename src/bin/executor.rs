@@ -1,7 +1,7 @@
 use std::io::{self, Write, BufReader, BufRead};
 use std::fs::File;
 
-use forth::{Forth, output_stream, kernels, debugger};
+use forth::{Forth, Error, output_stream, kernels, debugger, repl};
 
 
 struct FileStream {
@@ -82,8 +82,22 @@ fn main() {
     forth.kernel.init_io(StdinStream::new(), StdoutStream::new());
  
     let file_path = std::env::args().nth(1).expect("Please provide an input path");
-    let file = File::open(file_path).expect("File not found / able to be opened");
-    assert!(Ok(()) == forth.evaluate_stream(FileStream::new(BufReader::new(file))));
+    let file = File::open(&file_path).expect("File not found / able to be opened");
+    match forth.evaluate_stream(FileStream::new(BufReader::new(file))) {
+        Ok(()) => (),
+        Err(Error::Bye(code)) => std::process::exit(code as i32),
+        Err(error) => {
+            eprintln!("{}:{}: {:?}", file_path, forth.state.input_stream.line(), error);
+            std::process::exit(1)
+        }
+    }
 
-    assert!(Ok(()) == forth.evaluate_stream(StdinStream::new()));
+    // carry on interactively with whatever the file defined
+    match repl::Repl::new().and_then(|mut repl| repl.run(&mut forth)) {
+        Ok(code) => std::process::exit(code as i32),
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(1)
+        }
+    }
  }
\ No newline at end of file
@@ -1,8 +1,24 @@
 use std::io::{self, Write};
+use std::process;
 
-use forth::{Forth, output_stream, kernels, kernels::Kernel, debugger, profiler};
+use forth::{Forth, Error, output_stream, kernels, kernels::Kernel, debugger, profiler, trace, repl};
 
 
+const USAGE: &str = "usage: interpreter [options] [file...]
+
+Evaluates each file, and each piece of code given with -e, in the order they are given, and then reads more from
+the terminal.  Scripts that start with a #! line don't carry on into the terminal, so they can be run directly.
+
+options:
+    -e CODE        evaluate CODE
+    --no-repl      exit once everything has been evaluated, instead of reading from the terminal
+    --trace        write each instruction to standard error as it is executed
+    --profile      write a profile of what was executed to standard error when finished
+    --debug        stop in the debugger when there is an error
+    -h, --help     show this message
+
+The exit code is 1 if anything fails, or whatever BYE or (BYE) gives.";
+
 /**
  * An iterator that continually reads from standard input.
  */
@@ -46,16 +62,147 @@ impl output_stream::OutputStream for StdoutStream {
     }
 
     fn writeln(&mut self, m: &str) {
-        println!("{}", m); 
+        println!("{}", m);
     }
 }
 
-fn main() {
-    let mut forth = Forth::<profiler::ProfilerKernel<debugger::DebugKernel<kernels::DefaultKernel>>>::new(Default::default()).with_output_stream(StdoutStream::new());
-    forth.kernel.get_next_kernel().init_io(StdinStream::new(), StdoutStream::new());
+// keeps traces apart from what the program itself prints
+struct StderrStream {}
 
-    let result = forth.evaluate_stream(StdinStream::new());
-    println!("Finished evaluating: {:?}", result);
+impl output_stream::OutputStream for StderrStream {
+    fn write(&mut self, m: &str) {
+        eprint!("{}", m);
+    }
+
+    fn writeln(&mut self, m: &str) {
+        eprintln!("{}", m);
+    }
+}
+
+enum Source {
+    File(String),
+    Code(String),
+}
+
+struct Options {
+    sources: Vec<Source>,
+    repl: bool,
+    trace: bool,
+    profile: bool,
+    debug: bool,
+    help: bool,
+}
+
+fn parse_args<A: Iterator<Item = String>>(mut args: A) -> Result<Options, String> {
+    let mut options = Options { sources: Vec::new(), repl: true, trace: false, profile: false, debug: false, help: false };
+    let mut only_files = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            _ if only_files => options.sources.push(Source::File(arg)),
+            "-e" => options.sources.push(Source::Code(args.next().ok_or("-e needs some code to evaluate")?)),
+            "--no-repl" => options.repl = false,
+            "--trace" => options.trace = true,
+            "--profile" => options.profile = true,
+            "--debug" => options.debug = true,
+            "-h" | "--help" => options.help = true,
+            "--" => only_files = true,
+            _ if arg.starts_with('-') && arg.len() > 1 => return Err(format!("unknown option {}", arg)),
+            _ => options.sources.push(Source::File(arg))
+        }
+    }
+
+    Ok(options)
+}
 
-    forth.kernel.global_information.dump_statistics(&mut forth.state);
-}
\ No newline at end of file
+/**
+ * Evaluates everything that was given on the command line, and then carries on interactively if asked to.  Gives the exit
+ * code for the process.
+ */
+fn run<K: Kernel>(forth: &mut Forth<'_, '_, '_, K>, options: &Options) -> i32 {
+    let mut repl = options.repl;
+    for source in options.sources.iter() {
+        let (name, mut code) = match source {
+            Source::File(path) => match std::fs::read_to_string(path) {
+                Ok(code) => (path.as_str(), code),
+                Err(error) => {
+                    eprintln!("{}: {}", path, error);
+                    return 1
+                }
+            },
+            Source::Code(code) => ("-e", code.clone())
+        };
+        // a script run from its #! line shouldn't wait on the terminal afterwards.  the line is blanked out rather than
+        // removed, so that errors still give the right line numbers
+        if code.starts_with("#!") {
+            repl = false;
+            code.replace_range(..code.find('\n').unwrap_or(code.len()), "");
+        }
+
+        match forth.evaluate_stream(code.chars().collect::<Vec<_>>().into_iter()) {
+            Ok(()) => (),
+            Err(Error::Bye(code)) => return code as i32,
+            Err(error) => {
+                eprintln!("{}:{}: {:?}", name, forth.state.input_stream.line(), error);
+                return 1
+            }
+        }
+    }
+
+    if !repl {
+        return 0
+    }
+
+    let history = std::env::var_os("HOME").map(|home| std::path::PathBuf::from(home).join(".forth_history"));
+    let result = repl::Repl::new().map(|repl| match history {
+        Some(path) => repl.with_history(path),
+        None => repl
+    }).and_then(|mut repl| repl.run(forth));
+    match result {
+        Ok(code) => code as i32,
+        Err(error) => {
+            eprintln!("{}", error);
+            1
+        }
+    }
+}
+
+// the profiler goes in front of the other kernels, so that it can report on the whole run once it is over
+fn start<K: Kernel>(options: &Options, setup: impl FnOnce(&mut K)) -> i32 {
+    if options.profile {
+        let mut forth = Forth::<profiler::ProfilerKernel<K>>::new(Default::default()).with_output_stream(StdoutStream::new());
+        setup(forth.kernel.get_next_kernel());
+        let code = run(&mut forth, options);
+        eprint!("{}", forth.kernel.report(&forth.state));
+        code
+    } else {
+        let mut forth = Forth::<K>::new(Default::default()).with_output_stream(StdoutStream::new());
+        setup(&mut forth.kernel);
+        run(&mut forth, options)
+    }
+}
+
+fn main() {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(error) => {
+            eprintln!("{}\n{}", error, USAGE);
+            process::exit(2)
+        }
+    };
+    if options.help {
+        println!("{}", USAGE);
+        return
+    }
+
+    let code = match (options.trace, options.debug) {
+        (false, false) => start::<kernels::DefaultKernel>(&options, |_| ()),
+        (true, false) => start::<trace::TraceKernel<kernels::DefaultKernel>>(&options, |kernel| kernel.set_output_stream(StderrStream {})),
+        (false, true) => start::<debugger::DebugKernel<kernels::DefaultKernel>>(&options, |kernel| kernel.init_io(StdinStream::new(), StdoutStream::new())),
+        // errors are only handed on one kernel down the chain, so the debugger has to come before the tracer
+        (true, true) => start::<debugger::DebugKernel<trace::TraceKernel<kernels::DefaultKernel>>>(&options, |kernel| {
+            kernel.init_io(StdinStream::new(), StdoutStream::new());
+            kernel.get_next_kernel().set_output_stream(StderrStream {});
+        })
+    };
+    process::exit(code)
+}
@@ -1,21 +1,24 @@
+use std::fmt;
+
 use crate::evaluate::{self, definition};
 use crate::memory;
 use crate::environment::{generic_numbers, value, units};
+use std::rc::Rc;
 use super::CompiledInstructions;
 
 
-pub trait CloneCompiledInstruction<'a> {
-    fn clone_boxed(&self) -> Box<dyn CompiledInstruction<'a> + 'a>;
-}
-
-pub trait CompiledInstruction<'a>: CloneCompiledInstruction<'a> + ToString {
+pub trait CompiledInstruction<'a>: ToString {
     fn execute(&self, state: &mut evaluate::ForthState) -> evaluate::ForthResult;
-}
-
-impl <'a, T: 'a + Clone + CompiledInstruction<'a>> CloneCompiledInstruction<'a> for T {
-    fn clone_boxed(&self) -> Box<dyn CompiledInstruction<'a> + 'a> {
-        Box::new(self.clone())
-    }
+    // whether the instruction pops a flag off of the stack, and branches if it is false
+    fn is_conditional_branch(&self) -> bool { false }
+    // whether the instruction moves the instruction pointer anywhere other than the next cell
+    fn is_branch(&self) -> bool { self.is_conditional_branch() }
+    // where the instruction branches to, given the address it is stored at
+    fn branch_target(&self, _address: memory::Address) -> Option<memory::Address> { None }
+    // a copy of the instruction that branches to the given destination instead, if the instruction can't simply be moved along with its target
+    fn relocate(&self, _destination: memory::Address) -> Option<super::CompiledInstruction<'a>> { None }
+    // the definition that the instruction tail calls, which it enters in place of the definition it is in
+    fn tail_call_target(&self) -> Option<memory::Address> { None }
 }
 
 #[derive(Clone)]
@@ -50,6 +53,9 @@ impl<'a> CompiledInstruction<'a> for Branch {
     fn execute(&self, state: &mut evaluate::ForthState) -> evaluate::ForthResult {
         state.jump_to(self.0)
     }
+    fn is_branch(&self) -> bool { true }
+    fn branch_target(&self, _address: memory::Address) -> Option<memory::Address> { Some(self.0) }
+    fn relocate(&self, destination: memory::Address) -> Option<super::CompiledInstruction<'a>> { Some(Rc::new(Branch(destination))) }
 }
 impl ToString for Branch {
     fn to_string(&self) -> String {
@@ -67,6 +73,10 @@ impl<'a> CompiledInstruction<'a> for BranchFalse {
             state.jump_to(self.0)
         }
     }
+
+    fn is_conditional_branch(&self) -> bool { true }
+    fn branch_target(&self, _address: memory::Address) -> Option<memory::Address> { Some(self.0) }
+    fn relocate(&self, destination: memory::Address) -> Option<super::CompiledInstruction<'a>> { Some(Rc::new(BranchFalse(destination))) }
 }
 impl ToString for BranchFalse {
     fn to_string(&self) -> String {
@@ -85,6 +95,15 @@ impl RelativeBranchMeta {
             Self(false, destination.get() - instruction_pointer.get())
         }
     }
+
+    fn target(&self, address: memory::Address) -> memory::Address {
+        let instruction_pointer = address.plus_cell(units::Cells::one());
+        memory::Address::from_raw(if self.0 {
+            instruction_pointer.get() - self.1
+        } else {
+            instruction_pointer.get() + self.1
+        })
+    }
 }
 impl ToString for RelativeBranchMeta {
     fn to_string(&self) -> String {
@@ -99,6 +118,8 @@ impl<'a> CompiledInstruction<'a> for RelativeBranch {
     fn execute(&self, state: &mut evaluate::ForthState) -> evaluate::ForthResult {
         state.relative_jump_to(self.0.0, self.0.1)
     }
+    fn is_branch(&self) -> bool { true }
+    fn branch_target(&self, address: memory::Address) -> Option<memory::Address> { Some(self.0.target(address)) }
 }
 impl ToString for RelativeBranch {
     fn to_string(&self) -> String {
@@ -116,6 +137,9 @@ impl<'a> CompiledInstruction<'a> for RelativeBranchFalse {
             state.relative_jump_to(self.0.0, self.0.1)
         }
     }
+
+    fn is_conditional_branch(&self) -> bool { true }
+    fn branch_target(&self, address: memory::Address) -> Option<memory::Address> { Some(self.0.target(address)) }
 }
 impl ToString for RelativeBranchFalse {
     fn to_string(&self) -> String {
@@ -123,6 +147,81 @@ impl ToString for RelativeBranchFalse {
     }
 }
 
+/**
+ * The single operation performed by a fused instruction, which replaces a sequence of instructions.
+ */
+#[derive(Clone, Copy)]
+pub enum FusedOperation {
+    Push(generic_numbers::Number),
+    AddConstant(generic_numbers::Number),
+    Execute(definition::ExecutionToken),
+}
+
+impl fmt::Display for FusedOperation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Push(number) => write!(f, "push {}", number),
+            Self::AddConstant(number) => write!(f, "add {}", number),
+            Self::Execute(xt) => write!(f, "{}", xt.to_string())
+        }
+    }
+}
+
+/**
+ * An instruction produced by the optimizer, that performs the work of the following cells, and skips over them.
+ * The skipped cells are left intact, so anything that jumps into the middle of them still behaves the same.
+ */
+#[derive(Clone)]
+struct Fused(FusedOperation, units::Cells);
+impl<'a> CompiledInstruction<'a> for Fused {
+    fn execute(&self, state: &mut evaluate::ForthState) -> evaluate::ForthResult {
+        // skip first, so that a call made by the operation returns past the skipped cells
+        state.relative_jump_to(false, self.1.to_bytes())?;
+        match self.0 {
+            FusedOperation::Push(number) => {
+                state.stack.push(number);
+                Ok(())
+            }
+            FusedOperation::AddConstant(number) => {
+                let value = state.stack.pop::<generic_numbers::Number>()?;
+                state.stack.push(value.wrapping_add(number));
+                Ok(())
+            }
+            FusedOperation::Execute(xt) => state.execute(xt)
+        }
+    }
+
+    fn is_branch(&self) -> bool { true }
+}
+impl fmt::Display for Fused {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[fused] {} (skip {})", self.0, self.1.to_string())
+    }
+}
+
+/**
+ * A call at the very end of a definition, which jumps so the callee returns straight to the caller's caller.  The caller's
+ * stack frame is dropped first, unless the caller never had one.
+ */
+#[derive(Clone)]
+struct TailCall(memory::Address, bool);
+impl<'a> CompiledInstruction<'a> for TailCall {
+    fn execute(&self, state: &mut evaluate::ForthState) -> evaluate::ForthResult {
+        if self.1 {
+            state.return_stack.pop_frame()?;
+        }
+        state.jump_to(self.0)
+    }
+
+    fn is_branch(&self) -> bool { true }
+    fn tail_call_target(&self) -> Option<memory::Address> { Some(self.0) }
+}
+impl fmt::Display for TailCall {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "tail call {}", self.0.to_string())
+    }
+}
+
 pub struct InstructionCompiler<'b, 'a> {
     pub compiled_instructions: &'b mut CompiledInstructions<'a>
 }
@@ -152,7 +251,20 @@ impl<'b, 'a> InstructionCompiler<'b, 'a> {
         self.compile_instruction(MemPush(value))
     }
 
+    pub fn fused(&mut self, operation: FusedOperation, skip: units::Cells) -> definition::ExecutionToken {
+        self.compile_instruction(Fused(operation, skip))
+    }
+
+    pub fn tail_call(&mut self, destination: memory::Address) -> definition::ExecutionToken {
+        self.compile_instruction(TailCall(destination, true))
+    }
+
+    // a tail call from a definition that has no stack frame to drop
+    pub fn tail_jump(&mut self, destination: memory::Address) -> definition::ExecutionToken {
+        self.compile_instruction(TailCall(destination, false))
+    }
+
     fn compile_instruction<T: CompiledInstruction<'a> + 'a>(&mut self, instruction: T) -> definition::ExecutionToken {
-        self.compiled_instructions.add(Box::new(instruction))
+        self.compiled_instructions.add(Rc::new(instruction))
     }
 }
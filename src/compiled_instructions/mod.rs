@@ -1,10 +1,20 @@
 pub mod instruction_compiler;
 
+pub use instruction_compiler::FusedOperation;
+
+use std::rc::Rc;
+
 use crate::evaluate;
+use crate::memory;
 
 
-pub type CompiledInstruction<'a> = Box<dyn instruction_compiler::CompiledInstruction<'a> + 'a>;
+pub type CompiledInstruction<'a> = Rc<dyn instruction_compiler::CompiledInstruction<'a> + 'a>;
 
+/**
+ * An arena of every instruction that has been compiled, indexed by ExecutionToken::CompiledInstruction.  Instructions are never
+ * removed or changed once compiled, so handing out a shared reference to run one only costs a reference count, rather than
+ * an allocation.
+ */
 pub struct CompiledInstructions<'a> {
     compiled_instructions: Vec<CompiledInstruction<'a>>,
 }
@@ -16,11 +26,50 @@ impl<'a> CompiledInstructions<'a> {
 
     pub fn get(&self, execution_token: evaluate::definition::ExecutionToken) -> CompiledInstruction<'a> {
         match execution_token {
-            evaluate::definition::ExecutionToken::CompiledInstruction(offset) => self.compiled_instructions[offset].clone_boxed(),
+            evaluate::definition::ExecutionToken::CompiledInstruction(offset) => Rc::clone(&self.compiled_instructions[offset]),
             _ => panic!("attempted to execute invalid execution token")
         }
     }
 
+    pub fn is_conditional_branch(&self, execution_token: evaluate::definition::ExecutionToken) -> bool {
+        match execution_token {
+            evaluate::definition::ExecutionToken::CompiledInstruction(offset) => self.compiled_instructions[offset].is_conditional_branch(),
+            _ => false
+        }
+    }
+
+    pub fn is_branch(&self, execution_token: evaluate::definition::ExecutionToken) -> bool {
+        match execution_token {
+            evaluate::definition::ExecutionToken::CompiledInstruction(offset) => self.compiled_instructions[offset].is_branch(),
+            _ => false
+        }
+    }
+
+    pub fn branch_target(&self, execution_token: evaluate::definition::ExecutionToken, address: memory::Address) -> Option<memory::Address> {
+        match execution_token {
+            evaluate::definition::ExecutionToken::CompiledInstruction(offset) => self.compiled_instructions[offset].branch_target(address),
+            _ => None
+        }
+    }
+
+    pub fn tail_call_target(&self, execution_token: evaluate::definition::ExecutionToken) -> Option<memory::Address> {
+        match execution_token {
+            evaluate::definition::ExecutionToken::CompiledInstruction(offset) => self.compiled_instructions[offset].tail_call_target(),
+            _ => None
+        }
+    }
+
+    // moves a branch so that it targets destination, returning the execution token to use in its place
+    pub fn relocate(&mut self, execution_token: evaluate::definition::ExecutionToken, destination: memory::Address) -> evaluate::definition::ExecutionToken {
+        match execution_token {
+            evaluate::definition::ExecutionToken::CompiledInstruction(offset) => match self.compiled_instructions[offset].relocate(destination) {
+                Some(relocated) => self.add(relocated),
+                None => execution_token
+            },
+            _ => execution_token
+        }
+    }
+
     pub fn len(&self) -> usize {
         self.compiled_instructions.len()
     }
@@ -0,0 +1,327 @@
+use std::collections::HashMap;
+
+use crate::evaluate::{self, definition, kernels};
+use crate::environment::{memory::{self, MemorySegment}, value, units::{Bytes, Cells}};
+use crate::operations;
+
+
+// the name given to input that hasn't been given a source name by the host
+const DEFAULT_SOURCE: &str = "<input>";
+
+/**
+ * How many times a conditional branch jumped (its flag was false), and how many times it fell through.
+ */
+#[derive(Clone, Copy, Default, Debug)]
+pub struct BranchCoverage {
+    pub taken: usize,
+    pub not_taken: usize,
+}
+
+// a : definition, found by the prologue that : compiles into the start of every definition
+struct ColonDefinition {
+    name: String,
+    address: memory::Address,
+}
+
+/**
+ * Coverage information for a single : definition, as of when it was requested.
+ */
+pub struct DefinitionCoverage {
+    pub name: String,
+    pub address: memory::Address,
+    pub calls: usize,
+    pub cells: Cells,
+    pub executed_cells: Cells,
+    pub branches: Vec<(memory::Address, BranchCoverage)>,
+}
+
+/**
+ * A kernel that records which cells of each : definition were executed, and which way each conditional
+ * branch went.  Each compiled cell is tagged with the source line that compiled it, so that the results can
+ * be reported per line.  Line numbers restart whenever a new input stream is set, so a host evaluating
+ * several files should name each one with set_source before evaluating it.
+ */
+pub struct CoverageKernel<NK: kernels::Kernel> {
+    sources: Vec<String>,
+    current_source: usize,
+    // the source and line that each cell of the data space was compiled from
+    lines: HashMap<memory::Address, (usize, usize)>,
+    definitions: Vec<ColonDefinition>,
+    hits: HashMap<memory::Address, usize>,
+    branches: HashMap<memory::Address, BranchCoverage>,
+
+    // the top of the data space when the kernel was attached, before which everything is preset
+    program_start: memory::Address,
+    // the top of the data space and the line of the token being evaluated, as of the last evaluation
+    data_space_top: memory::Address,
+    previous_line: usize,
+
+    next_kernel: NK
+}
+
+impl<NK: kernels::Kernel> CoverageKernel<NK> {
+    /**
+     * Name the source of the code evaluated from now on, for example the path of the file being evaluated.
+     */
+    pub fn set_source(&mut self, name: &str) {
+        self.current_source = match self.sources.iter().position(|source| source == name) {
+            Some(index) => index,
+            None => {
+                self.sources.push(name.to_string());
+                self.sources.len() - 1
+            }
+        };
+    }
+
+    pub fn hits(&self) -> &HashMap<memory::Address, usize> {
+        &self.hits
+    }
+
+    /**
+     * The coverage of every : definition compiled so far, in the order they were defined.
+     */
+    pub fn definition_coverage(&self, state: &evaluate::ForthState) -> Vec<DefinitionCoverage> {
+        self.definitions.iter().map(|colon_definition| {
+            let cells = definition_length(state, colon_definition.address);
+            let addresses = (0..cells.get_cells()).map(|i| colon_definition.address.plus_cell(Cells::cells(i)));
+
+            let mut executed_cells = Cells::zero();
+            let mut branches = Vec::new();
+            for address in addresses {
+                if self.hits.contains_key(&address) {
+                    executed_cells += Cells::one();
+                }
+
+                // a branch on a constant flag, which is how ELSE jumps, is unconditional
+                let is_branch = state.data_space.read::<definition::ExecutionToken>(address)
+                    .map(|xt| state.compiled_instructions.is_conditional_branch(xt))
+                    .unwrap_or(false);
+                let is_constant = matches!(state.data_space.read::<definition::ExecutionToken>(address.minus_cell(Cells::one())), Ok(definition::ExecutionToken::Number(_)));
+                if is_branch && !is_constant {
+                    branches.push((address, self.branches.get(&address).copied().unwrap_or_default()));
+                }
+            }
+
+            DefinitionCoverage {
+                name: colon_definition.name.clone(),
+                address: colon_definition.address,
+                calls: self.hits.get(&colon_definition.address).copied().unwrap_or(0),
+                cells, executed_cells, branches
+            }
+        }).collect()
+    }
+
+    /**
+     * A text report with a line for each : definition.
+     */
+    pub fn report(&self, state: &evaluate::ForthState) -> String {
+        let mut report = format!("{:>30} {:>8} {:>14} {:>14}\n", "definition", "calls", "cells", "branches");
+        for coverage in self.definition_coverage(state) {
+            let branches = coverage.branches.iter().map(|(_, branch)| (branch.taken > 0) as usize + (branch.not_taken > 0) as usize).sum::<usize>();
+            report.push_str(&format!("{:>30} {:>8} {:>14} {:>14}\n",
+                coverage.name, coverage.calls,
+                format!("{}/{}", coverage.executed_cells.get_cells(), coverage.cells.get_cells()),
+                format!("{}/{}", branches, coverage.branches.len() * 2)));
+        }
+
+        report
+    }
+
+    /**
+     * The coverage in the lcov tracefile format, with a record for each source.  A line is hit as many times as
+     * the most executed cell compiled from it.
+     */
+    pub fn lcov(&self, state: &evaluate::ForthState) -> String {
+        let coverage = self.definition_coverage(state);
+        let mut tracefile = String::from("TN:\n");
+
+        for (source_index, source) in self.sources.iter().enumerate() {
+            let line_of = |address: memory::Address| self.lines.get(&address)
+                .filter(|(source, _)| *source == source_index)
+                .map(|(_, line)| *line);
+
+            let definitions = coverage.iter().filter_map(|coverage| line_of(coverage.address).map(|line| (line, coverage))).collect::<Vec<_>>();
+            if definitions.is_empty() {
+                continue
+            }
+
+            tracefile.push_str(&format!("SF:{}\n", source));
+            for (line, coverage) in definitions.iter() {
+                tracefile.push_str(&format!("FN:{},{}\n", line, coverage.name));
+            }
+            for (_, coverage) in definitions.iter() {
+                tracefile.push_str(&format!("FNDA:{},{}\n", coverage.calls, coverage.name));
+            }
+            tracefile.push_str(&format!("FNF:{}\n", definitions.len()));
+            tracefile.push_str(&format!("FNH:{}\n", definitions.iter().filter(|(_, coverage)| coverage.calls > 0).count()));
+
+            // branches, numbered in order of appearance
+            let mut branches_found = 0;
+            let mut branches_hit = 0;
+            for (block, (address, branch)) in definitions.iter().flat_map(|(_, coverage)| coverage.branches.iter()).enumerate() {
+                if let Some(line) = line_of(*address) {
+                    let executed = self.hits.contains_key(address);
+                    for (index, count) in [branch.taken, branch.not_taken].iter().enumerate() {
+                        let count = if executed { count.to_string() } else { "-".to_string() };
+                        tracefile.push_str(&format!("BRDA:{},{},{},{}\n", line, block, index, count));
+                    }
+                    branches_found += 2;
+                    branches_hit += (branch.taken > 0) as usize + (branch.not_taken > 0) as usize;
+                }
+            }
+            tracefile.push_str(&format!("BRF:{}\n", branches_found));
+            tracefile.push_str(&format!("BRH:{}\n", branches_hit));
+
+            // lines, taking the most executed cell of each line
+            let mut line_hits: HashMap<usize, usize> = HashMap::new();
+            for (_, coverage) in definitions.iter() {
+                for address in (0..coverage.cells.get_cells()).map(|i| coverage.address.plus_cell(Cells::cells(i))) {
+                    if let Some(line) = line_of(address) {
+                        let hits = line_hits.entry(line).or_insert(0);
+                        *hits = (*hits).max(self.hits.get(&address).copied().unwrap_or(0));
+                    }
+                }
+            }
+            let mut lines = line_hits.into_iter().collect::<Vec<_>>();
+            lines.sort_unstable();
+            for (line, hits) in lines.iter() {
+                tracefile.push_str(&format!("DA:{},{}\n", line, hits));
+            }
+            tracefile.push_str(&format!("LF:{}\n", lines.len()));
+            tracefile.push_str(&format!("LH:{}\n", lines.iter().filter(|(_, hits)| *hits > 0).count()));
+            tracefile.push_str("end_of_record\n");
+        }
+
+        tracefile
+    }
+
+    /**
+     * Tag the cells compiled since the last evaluation with the line that compiled them.  Those cells were either
+     * compiled by executing the previous instruction, or, if nothing is being executed now, the last of them was
+     * compiled when the current token was read.
+     */
+    fn record_compilation(&mut self, state: &evaluate::ForthState) {
+        let top = state.data_space.top();
+        let line = state.input_stream.line();
+
+        let mut address = self.data_space_top;
+        while address.less_than(top) {
+            let compiled_by_current_token = state.current_instruction().is_none() && address.plus_cell(Cells::one()).equals(top);
+            let line = if compiled_by_current_token { line } else { self.previous_line };
+            self.lines.insert(address, (self.current_source, line));
+            address.increment_cell();
+        }
+
+        self.data_space_top = top;
+        self.previous_line = line;
+    }
+
+    // check whether a new : definition was just started
+    fn record_definition(&mut self, state: &evaluate::ForthState) {
+        let execution_token = state.definitions.most_recent_definition().execution_token;
+        let address = match execution_token {
+            definition::ExecutionToken::Definition(address) => address,
+            _ => return
+        };
+
+        let prologue = definition::ExecutionToken::LeafOperation(operations::stack_operations::push_stack_frame);
+        let is_colon_definition = state.data_space.read::<definition::ExecutionToken>(address).map(|xt| xt == prologue).unwrap_or(false);
+        if is_colon_definition && !address.less_than(self.program_start) && !self.definitions.iter().any(|colon_definition| colon_definition.address == address) {
+            let name = state.definitions.debug_only_get_name(execution_token).unwrap_or_default();
+            self.definitions.push(ColonDefinition { name, address });
+        }
+    }
+}
+
+// the number of cells in a : definition, which ; writes into the cell just before it
+fn definition_length(state: &evaluate::ForthState, address: memory::Address) -> Cells {
+    state.data_space.read::<Bytes>(address.minus_cell(Cells::one()))
+        .map(|length| length.to_cells())
+        .unwrap_or(Cells::zero())
+}
+
+impl<NK: kernels::Kernel> kernels::Kernel for CoverageKernel<NK> {
+    type NextKernel = NK;
+    fn new(state: &mut evaluate::ForthState) -> Self {
+        Self {
+            sources: vec![DEFAULT_SOURCE.to_string()],
+            current_source: 0,
+            lines: HashMap::new(),
+            definitions: Vec::new(),
+            hits: HashMap::new(),
+            branches: HashMap::new(),
+            program_start: state.data_space.top(),
+            data_space_top: state.data_space.top(),
+            previous_line: 1,
+            next_kernel: NK::new(state)
+        }
+    }
+
+    fn get_next_kernel(&mut self) -> &mut Self::NextKernel { &mut self.next_kernel }
+
+    fn evaluate(&mut self, state: &mut evaluate::ForthState) -> evaluate::ForthResult {
+        self.record_compilation(state);
+        self.record_definition(state);
+
+        if let (Some(address), Some(current_instruction)) = (state.instruction_pointer(), state.current_instruction()) {
+            *self.hits.entry(address).or_insert(0) += 1;
+
+            // conditional branches jump when the flag on top of the stack is false
+            if state.compiled_instructions.is_conditional_branch(current_instruction) {
+                if let Ok(flag) = state.stack.peek::<value::Value>() {
+                    let branch = self.branches.entry(address).or_default();
+                    if flag.to_number() == 0 {
+                        branch.taken += 1;
+                    } else {
+                        branch.not_taken += 1;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle_error(&mut self, state: &mut evaluate::ForthState, error: evaluate::Error) -> evaluate::ForthResult {
+        match error {
+            evaluate::Error::UnknownWord(word) if &word == "COVERAGE_REPORT" => {
+                let report = self.report(state);
+                state.output_stream.write(&report);
+                Ok(())
+            }
+            evaluate::Error::UnknownWord(word) if &word == "COVERAGE_LCOV" => {
+                let tracefile = self.lcov(state);
+                state.output_stream.write(&tracefile);
+                Ok(())
+            }
+            error => Err(error)
+        }
+    }
+}
+
+#[test]
+fn branch_coverage_test() {
+    let mut f = evaluate::Forth::<CoverageKernel<kernels::DefaultKernel>>::new(Default::default());
+    f.kernel.set_source("test.f");
+    assert!(f.evaluate_string(": sign\n  0< if -1 else 1 then ;\n: unused 1 ;").is_ok());
+    assert!(f.evaluate_string("5 sign 7 sign").is_ok());
+
+    let coverage = f.kernel.definition_coverage(&f.state);
+    assert_eq!(coverage.len(), 2);
+    assert_eq!(coverage[0].name, "sign");
+    assert_eq!(coverage[0].calls, 2);
+    assert_eq!(coverage[0].branches.len(), 1);
+    assert_eq!(coverage[0].branches[0].1.taken, 2);
+    assert_eq!(coverage[0].branches[0].1.not_taken, 0);
+    assert!(coverage[0].executed_cells < coverage[0].cells);
+    assert_eq!(coverage[1].executed_cells, Cells::zero());
+
+    let tracefile = f.kernel.lcov(&f.state);
+    assert!(tracefile.contains("SF:test.f\n"));
+    assert!(tracefile.contains("FN:1,sign\n"));
+    assert!(tracefile.contains("FNDA:2,sign\n"));
+    assert!(tracefile.contains("FNDA:0,unused\n"));
+    assert!(tracefile.contains("BRDA:2,0,0,2\n"));
+    assert!(tracefile.contains("BRDA:2,0,1,0\n"));
+    assert!(tracefile.contains("DA:3,0\n"));
+}
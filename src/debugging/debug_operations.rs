@@ -67,10 +67,9 @@ fn read_from_address(debug_target: &evaluate::ForthState, address: memory::Addre
 }
 
 fn get_variables<'b>(debug_target: &'b evaluate::ForthState) -> Vec<(&'b String, memory::Address)> {
-    debug_target.definitions.debug_only_get_nametag_map().iter()
-        .map(|(word, index)| (word, debug_target.definitions.get_by_index(*index).unwrap().execution_token))
-        .filter_map(|(word, execution_token)| match execution_token { 
-            evaluate::definition::ExecutionToken::Number(addr) => Some((word, memory::Address::from_raw(Bytes::from(addr)))),
+    debug_target.definitions.debug_only_get_names()
+        .filter_map(|(word, index)| match debug_target.definitions.get_by_index(index).map(|definition| definition.execution_token) { 
+            Ok(evaluate::definition::ExecutionToken::Number(addr)) => Some((word, memory::Address::from_raw(Bytes::from(addr)))),
             _ => None
         }).collect::<Vec<_>>()
 }
@@ -137,7 +136,7 @@ fn print_memory_formatted(debug_target: &evaluate::ForthState, address: memory::
  * Debug operations.
  */
 pub(in super) fn view_memory_region(debugger_state: &mut debugger::DebugState, debug_target: &mut evaluate::ForthState) -> evaluate::ForthResult {
-    let name = debugger_state.forth.state.input_stream.next_word()?;
+    let name = debugger_state.forth.state.input_stream.next_word()?.to_uppercase();
     for mapping in debug_target.memory_map().get_entries().iter() {
         if let Some(mapping_name) = mapping.name.map(|x| x.to_uppercase()) {
             if mapping_name == name {
@@ -166,7 +165,7 @@ pub(in super) fn view_memory_map(debugger_state: &mut debugger::DebugState, debu
 
 pub(in super) fn examine_memory(debugger_state: &mut debugger::DebugState, debug_target: &mut evaluate::ForthState) -> evaluate::ForthResult {
     let address: memory::Address = debugger_state.forth.state.stack.pop()?;
-    let format = debugger_state.forth.state.input_stream.next_word()?;
+    let format = debugger_state.forth.state.input_stream.next_word()?.to_uppercase();
     debugger_state.forth.state.output_stream.writeln(&read_from_address(debug_target, address, &format[..])?);
 
     Ok(())
@@ -200,6 +199,12 @@ pub(in super) fn view_state(debugger_state: &mut debugger::DebugState, debug_tar
     for (word, value) in &[("execution mode", execution_mode), ("current instruction", current_instruction), ("instruction pointer", instruction_pointer)] {
         debugger_state.forth.state.output_stream.writeln(&format!("{:>20}: {}", word, value));
     }
+
+    debugger_state.forth.state.output_stream.writeln("------------------------------------------------------");
+    for register in debug_target.internal_state_memory().registers() {
+        let value = register.read(debug_target).to_number();
+        debugger_state.forth.state.output_stream.writeln(&format!("{:>20}: {}", register.name.to_lowercase(), value));
+    }
     
     debugger_state.forth.state.output_stream.writeln("------------------------------------------------------");
     if let Some(instruction_pointer) = debug_target.instruction_pointer() {
@@ -221,8 +226,8 @@ pub(in super) fn view_state(debugger_state: &mut debugger::DebugState, debug_tar
 }
 
 pub(in super) fn all_commands(debugger_state: &mut debugger::DebugState, debug_target: &mut evaluate::ForthState) -> evaluate::ForthResult {
-    for (word, index) in debug_target.definitions.debug_only_get_nametag_map().iter() {
-        let definition = debug_target.definitions.get_by_index(*index)?;
+    for (word, index) in debug_target.definitions.debug_only_get_names() {
+        let definition = debug_target.definitions.get_by_index(index)?;
         let immediate_string = if definition.immediate {
             "immediate"
         } else {
@@ -285,6 +290,16 @@ pub(in super) fn see(debugger_state: &mut debugger::DebugState, debug_target: &m
         end.increment_cell();
 
         print_memory_formatted(debug_target, address, Some(end), debugger_state.forth.state.get_forth_io());
+
+        // show what the definition looked like before the optimizer rewrote it
+        if let Some(original) = debug_target.optimizer.original_definition(address) {
+            let io = debugger_state.forth.state.get_forth_io();
+            io.output_stream.writeln("before optimization:");
+            for (i, value) in original.iter().enumerate() {
+                let current_address = address.plus_cell(Cells::cells(i));
+                io.output_stream.writeln(&format!("{:<7} |         {:<30}", stringify_address(current_address), print_value_helper(debug_target, *value, 0, 4)));
+            }
+        }
     }
     
     Ok(())
@@ -29,7 +29,8 @@ impl <'a, 'i, 'o> DebugState<'a, 'i, 'o> {
     }
 
     fn debug(&mut self, state: &mut evaluate::ForthState) {
-        debug_operations::view_state(self, state).unwrap();
+        let result = debug_operations::view_state(self, state);
+        self.report(result);
         // await and execute debug commands
         self.debugging = true;
         while self.debugging {
@@ -42,11 +43,19 @@ impl <'a, 'i, 'o> DebugState<'a, 'i, 'o> {
                 } else {
                     Err(e)
                 });
-            if let Err(error) = result {
-                self.forth.state.output_stream.writeln(&format!("Error: {:?}", error));
-            }
+            self.report(result);
         }
-    }    
+    }
+
+    /**
+     * Errors raised while debugging belong to the debugger, not the debug target, so they are
+     * written to the debugger's output instead of being propagated.
+     */
+    fn report(&mut self, result: evaluate::ForthResult) {
+        if let Err(error) = result {
+            self.forth.state.output_stream.writeln(&format!("Error: {:?}", error));
+        }
+    }
 }
 
 pub struct DebugKernel<'a, 'i, 'o, NK: kernels::Kernel> {
@@ -103,7 +112,7 @@ impl<'a, 'i, 'o, NK: kernels::Kernel> kernels::Kernel for DebugKernel<'a, 'i, 'o
 
     fn handle_error(&mut self, state: &mut evaluate::ForthState, error: evaluate::Error) -> evaluate::ForthResult { 
         match error {
-            evaluate::Error::TokenStreamEmpty | evaluate::Error::Halt => return Err(error),
+            evaluate::Error::TokenStreamEmpty | evaluate::Error::Halt | evaluate::Error::Bye(_) => return Err(error),
             _ => ()
         }
 
@@ -1,3 +1,5 @@
 pub mod debugger;
 pub mod profiler;
+pub mod coverage;
+pub mod trace;
 mod debug_operations;
\ No newline at end of file
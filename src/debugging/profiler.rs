@@ -1,17 +1,89 @@
+use std::cmp;
 use std::collections::HashMap;
+use std::time::{Duration, Instant};
 
 use crate::evaluate::{self, definition, kernels};
+use crate::environment::memory;
+use crate::environment::units::Cells;
 use super::debug_operations;
 
 
+// the name given to the root of the call tree, which is where instructions run straight from the input stream end up
+const ROOT_NAME: &str = "[interpreter]";
+
+/**
+ * Statistics gathered for a single definition.  Inclusive numbers count everything executed between the call
+ * and the return, while exclusive numbers only count the instructions of the definition itself.  Recursive
+ * calls only contribute to the inclusive numbers once, from the outermost call.
+ */
+#[derive(Clone, Copy, Default, Debug)]
+pub struct DefinitionStatistics {
+    pub calls: usize,
+    pub inclusive_instructions: usize,
+    pub exclusive_instructions: usize,
+    pub inclusive_time: Duration,
+    pub exclusive_time: Duration,
+}
+
+/**
+ * An edge of the call graph, from a caller to a callee.  The instructions and time are inclusive, summed over every call.
+ */
+#[derive(Clone, Copy, Default, Debug)]
+pub struct CallEdge {
+    pub calls: usize,
+    pub instructions: usize,
+    pub time: Duration,
+}
+
+// a node of the calling context tree, used to produce the folded stacks.  the root node has no execution token
+struct CallNode {
+    execution_token: Option<definition::ExecutionToken>,
+    children: HashMap<definition::ExecutionToken, usize>,
+    instructions: usize,
+}
+
+impl CallNode {
+    fn new(execution_token: Option<definition::ExecutionToken>) -> Self {
+        Self { execution_token, children: HashMap::new(), instructions: 0 }
+    }
+}
+
+// a definition that has been called, and has not yet returned
+struct CallFrame {
+    execution_token: definition::ExecutionToken,
+    // the instruction pointer that the definition returns to, which is none if it was called from the input stream
+    return_address: Option<memory::Address>,
+    // the depth of the return stack before the call, which it returns to once the definition returns
+    return_stack_depth: usize,
+    node: usize,
+    instruction_count: usize,
+    start: Instant,
+    callee_time: Duration,
+}
+
 pub struct ProfilerInformation {
     total_instruction_count: usize,
     instruction_counts: HashMap<definition::ExecutionToken, usize>,
+
+    definitions: HashMap<definition::ExecutionToken, DefinitionStatistics>,
+    call_graph: HashMap<(Option<definition::ExecutionToken>, definition::ExecutionToken), CallEdge>,
+    call_tree: Vec<CallNode>,
+    // a shadow of the return stack, only tracking calls to definitions
+    call_stack: Vec<CallFrame>,
+    last_instruction: Instant,
 }
 
 impl ProfilerInformation {
     fn new() -> Self {
-        Self { total_instruction_count: 0, instruction_counts: HashMap::new() }
+        Self {
+            total_instruction_count: 0,
+            instruction_counts: HashMap::new(),
+            definitions: HashMap::new(),
+            call_graph: HashMap::new(),
+            call_tree: vec![CallNode::new(None)],
+            call_stack: Vec::new(),
+            last_instruction: Instant::now(),
+        }
     }
 
     pub fn record_instruction(&mut self, instruction: definition::ExecutionToken) {
@@ -22,12 +94,242 @@ impl ProfilerInformation {
         }
     }
 
+    /**
+     * Records the instruction that is about to be executed, along with any calls and returns that it implies.
+     * This must be called before the instruction is executed, while the instruction pointer still points at it.
+     */
+    pub fn record(&mut self, state: &evaluate::ForthState, instruction: definition::ExecutionToken) {
+        let now = Instant::now();
+        self.last_instruction = now;
+        self.return_to(state, now);
+        self.record_instruction(instruction);
+
+        // the instruction belongs to whichever definition is currently executing
+        let node = match self.call_stack.last() {
+            Some(frame) => {
+                self.definitions.entry(frame.execution_token).or_default().exclusive_instructions += 1;
+                frame.node
+            }
+            None => 0
+        };
+        self.call_tree[node].instructions += 1;
+
+        if let definition::ExecutionToken::Definition(_) = instruction {
+            // the instruction pointer will have been incremented past the call by the time it is pushed to the return stack
+            let return_address = state.instruction_pointer().map(|address| address.plus_cell(Cells::one()));
+            self.enter(instruction, node, return_address, state.return_stack.len().get_cells(), now);
+        } else if let Some(target) = state.compiled_instructions.tail_call_target(instruction) {
+            // a tail call leaves the caller, and the callee takes its place, returning to wherever the caller would have
+            if let Some(frame) = self.call_stack.last() {
+                let (return_address, return_stack_depth) = (frame.return_address, frame.return_stack_depth);
+                self.leave(now);
+                let parent = self.call_stack.last().map_or(0, |frame| frame.node);
+                self.enter(definition::ExecutionToken::Definition(target), parent, return_address, return_stack_depth, now);
+            }
+        }
+    }
+
+    /**
+     * Closes any definitions that have already returned, given the current state.  If the state is awaiting
+     * input, then every definition has returned.
+     */
+    pub fn synchronize(&mut self, state: &evaluate::ForthState) {
+        let last_instruction = self.last_instruction;
+        self.return_to(state, last_instruction);
+    }
+
+    /**
+     * Closes every definition that has not yet returned, for when recording stops in the middle of execution.
+     */
+    pub fn finish(&mut self) {
+        let last_instruction = self.last_instruction;
+        self.finish_at(last_instruction);
+    }
+
+    fn enter(&mut self, execution_token: definition::ExecutionToken, parent: usize, return_address: Option<memory::Address>, return_stack_depth: usize, now: Instant) {
+        let next_node = self.call_tree.len();
+        let node = *self.call_tree[parent].children.entry(execution_token).or_insert(next_node);
+        if node == next_node {
+            self.call_tree.push(CallNode::new(Some(execution_token)));
+        }
+
+        self.definitions.entry(execution_token).or_default().calls += 1;
+        let caller = self.call_stack.last().map(|frame| frame.execution_token);
+        self.call_graph.entry((caller, execution_token)).or_default().calls += 1;
+
+        self.call_stack.push(CallFrame {
+            execution_token,
+            return_address,
+            return_stack_depth,
+            node,
+            instruction_count: self.total_instruction_count,
+            start: now,
+            callee_time: Duration::default(),
+        });
+    }
+
+    fn leave(&mut self, now: Instant) {
+        let frame = match self.call_stack.pop() {
+            Some(frame) => frame,
+            None => return
+        };
+
+        let instructions = self.total_instruction_count - frame.instruction_count;
+        let time = now.duration_since(frame.start);
+        let recursive = self.call_stack.iter().any(|caller| caller.execution_token == frame.execution_token);
+
+        let statistics = self.definitions.entry(frame.execution_token).or_default();
+        statistics.exclusive_time += time.checked_sub(frame.callee_time).unwrap_or_default();
+        if !recursive {
+            statistics.inclusive_instructions += instructions;
+            statistics.inclusive_time += time;
+        }
+
+        let caller = self.call_stack.last_mut();
+        let edge = self.call_graph.entry((caller.as_ref().map(|caller| caller.execution_token), frame.execution_token)).or_default();
+        edge.instructions += instructions;
+        edge.time += time;
+        if let Some(caller) = caller {
+            caller.callee_time += time;
+        }
+    }
+
+    fn return_to(&mut self, state: &evaluate::ForthState, now: Instant) {
+        match state.instruction_pointer() {
+            // awaiting input, so everything has returned
+            None => self.finish_at(now),
+            Some(instruction_pointer) => while let Some(frame) = self.call_stack.last() {
+                if frame.return_address == Some(instruction_pointer) && frame.return_stack_depth == state.return_stack.len().get_cells() {
+                    self.leave(now);
+                } else {
+                    break
+                }
+            }
+        }
+    }
+
+    fn finish_at(&mut self, now: Instant) {
+        while !self.call_stack.is_empty() {
+            self.leave(now);
+        }
+    }
+
+    pub fn total_instruction_count(&self) -> usize {
+        self.total_instruction_count
+    }
+
+    pub fn definition_statistics(&self) -> &HashMap<definition::ExecutionToken, DefinitionStatistics> {
+        &self.definitions
+    }
+
+    /**
+     * The call graph, keyed by caller and callee.  A caller of None means the callee was called from the input stream.
+     */
+    pub fn call_graph(&self) -> &HashMap<(Option<definition::ExecutionToken>, definition::ExecutionToken), CallEdge> {
+        &self.call_graph
+    }
+
     pub fn dump_statistics(&self, state: &mut evaluate::ForthState) {
         state.output_stream.writeln(&format!("total instructions: {}", self.total_instruction_count));
         for (instruction, count) in self.instruction_counts.iter() {
             state.output_stream.writeln(&format!("   {:>30}: {}", debug_operations::stringify_execution_token(state, *instruction), count));
         }
     }
+
+    /**
+     * A text report of every definition called, sorted by inclusive instruction count, followed by the call graph.
+     */
+    pub fn report(&self, state: &evaluate::ForthState) -> String {
+        let mut definitions = self.definitions.iter().collect::<Vec<_>>();
+        definitions.sort_by_key(|(_, statistics)| cmp::Reverse(statistics.inclusive_instructions));
+
+        let mut report = format!("total instructions: {}\n", self.total_instruction_count);
+        report.push_str(&format!("{:>30} {:>10} {:>12} {:>12} {:>14} {:>14}\n", "definition", "calls", "inclusive", "exclusive", "inclusive time", "exclusive time"));
+        for (execution_token, statistics) in definitions {
+            report.push_str(&format!("{:>30} {:>10} {:>12} {:>12} {:>14?} {:>14?}\n",
+                name(state, Some(*execution_token)), statistics.calls, statistics.inclusive_instructions, statistics.exclusive_instructions,
+                statistics.inclusive_time, statistics.exclusive_time));
+        }
+
+        let mut edges = self.call_graph.iter().collect::<Vec<_>>();
+        edges.sort_by_key(|(_, edge)| cmp::Reverse(edge.instructions));
+
+        report.push_str("\ncall graph:\n");
+        for ((caller, callee), edge) in edges {
+            report.push_str(&format!("{:>30} -> {:<30} {:>10} calls {:>12} instructions {:>14?}\n",
+                name(state, *caller), name(state, Some(*callee)), edge.calls, edge.instructions, edge.time));
+        }
+
+        report
+    }
+
+    /**
+     * The exclusive instruction counts of every call stack, in the folded format consumed by flamegraph tools.
+     */
+    pub fn folded_stacks(&self, state: &evaluate::ForthState) -> String {
+        fn fold(information: &ProfilerInformation, state: &evaluate::ForthState, node: usize, path: &mut Vec<String>, folded: &mut String) {
+            let node = &information.call_tree[node];
+            // semicolons separate frames in the folded format, so they can't appear in the names
+            path.push(name(state, node.execution_token).replace(';', ","));
+            if node.instructions > 0 {
+                folded.push_str(&format!("{} {}\n", path.join(";"), node.instructions));
+            }
+
+            let mut children = node.children.values().copied().collect::<Vec<_>>();
+            children.sort_unstable();
+            for child in children {
+                fold(information, state, child, path, folded);
+            }
+            path.pop();
+        }
+
+        let mut folded = String::new();
+        fold(self, state, 0, &mut Vec::new(), &mut folded);
+        folded
+    }
+
+    /**
+     * The definition statistics and call graph as a JSON object.  Times are in nanoseconds.
+     */
+    pub fn json(&self, state: &evaluate::ForthState) -> String {
+        let definitions = self.definitions.iter().map(|(execution_token, statistics)| format!(
+            "{{\"name\":{},\"calls\":{},\"inclusive_instructions\":{},\"exclusive_instructions\":{},\"inclusive_time_ns\":{},\"exclusive_time_ns\":{}}}",
+            json_string(&name(state, Some(*execution_token))), statistics.calls, statistics.inclusive_instructions, statistics.exclusive_instructions,
+            statistics.inclusive_time.as_nanos(), statistics.exclusive_time.as_nanos()
+        )).collect::<Vec<_>>();
+
+        let edges = self.call_graph.iter().map(|((caller, callee), edge)| format!(
+            "{{\"caller\":{},\"callee\":{},\"calls\":{},\"instructions\":{},\"time_ns\":{}}}",
+            json_string(&name(state, *caller)), json_string(&name(state, Some(*callee))), edge.calls, edge.instructions, edge.time.as_nanos()
+        )).collect::<Vec<_>>();
+
+        format!("{{\"total_instructions\":{},\"definitions\":[{}],\"call_graph\":[{}]}}", self.total_instruction_count, definitions.join(","), edges.join(","))
+    }
+}
+
+/**
+ * Helper functions
+ */
+fn name(state: &evaluate::ForthState, execution_token: Option<definition::ExecutionToken>) -> String {
+    match execution_token {
+        Some(execution_token) => state.definitions.debug_only_get_name(execution_token)
+            .unwrap_or_else(|| debug_operations::stringify_execution_token(state, execution_token)),
+        None => ROOT_NAME.to_string()
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut escaped = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c)
+        }
+    }
+    escaped.push('"');
+    escaped
 }
 
 #[derive(PartialEq, Eq)]
@@ -57,6 +359,27 @@ pub struct ProfilerKernel<KN: kernels::Kernel> {
     next_kernel: KN
 }
 
+/**
+ * Exports of the global profiling information.  These first close any definitions that have returned since
+ * the last recorded instruction.
+ */
+impl<KN: kernels::Kernel> ProfilerKernel<KN> {
+    pub fn report(&mut self, state: &evaluate::ForthState) -> String {
+        self.global_information.synchronize(state);
+        self.global_information.report(state)
+    }
+
+    pub fn folded_stacks(&mut self, state: &evaluate::ForthState) -> String {
+        self.global_information.synchronize(state);
+        self.global_information.folded_stacks(state)
+    }
+
+    pub fn json(&mut self, state: &evaluate::ForthState) -> String {
+        self.global_information.synchronize(state);
+        self.global_information.json(state)
+    }
+}
+
 impl<KN: kernels::Kernel> kernels::Kernel for ProfilerKernel<KN> {
     type NextKernel = KN;
     fn new(state: &mut evaluate::ForthState) -> Self {         
@@ -73,10 +396,9 @@ impl<KN: kernels::Kernel> kernels::Kernel for ProfilerKernel<KN> {
 
     fn evaluate(&mut self, state: &mut evaluate::ForthState) -> evaluate::ForthResult {
         state.current_instruction().map(|current_instruction| {
-            self.global_information.record_instruction(current_instruction);
+            self.global_information.record(state, current_instruction);
 
-            if self.recording && self.profiling_word != None {
-                let profiling_word = self.profiling_word.as_ref().unwrap();
+            if let (true, Some(profiling_word)) = (self.recording, &self.profiling_word) {
                 self.recording = if profiling_word.manually_called {
                     /* 
                     * if the profiling word was called manually, its return is marked by there being no 
@@ -90,6 +412,11 @@ impl<KN: kernels::Kernel> kernels::Kernel for ProfilerKernel<KN> {
                     */
                     profiling_word.stack_depth < state.return_stack.len().get_cells()
                 };
+
+                // the profiling word has returned, so close out whatever is left of its call stack
+                if !self.recording {
+                    self.local_information.finish();
+                }
             }
 
             // check if the current insturction matches the profiling word, and that we aren't already recoring
@@ -102,7 +429,7 @@ impl<KN: kernels::Kernel> kernels::Kernel for ProfilerKernel<KN> {
             }
 
             if self.recording {
-                self.local_information.record_instruction(current_instruction);
+                self.local_information.record(state, current_instruction);
             }
         });
 
@@ -120,6 +447,7 @@ impl<KN: kernels::Kernel> kernels::Kernel for ProfilerKernel<KN> {
             evaluate::Error::UnknownWord(word) if &word == "PROFILE_END" => {
                 self.recording = false;
                 self.profiling_word = None;
+                self.local_information.finish();
                 self.local_information.dump_statistics(state);
                 Ok(())
             }
@@ -130,6 +458,25 @@ impl<KN: kernels::Kernel> kernels::Kernel for ProfilerKernel<KN> {
                 self.local_information.dump_statistics(state);
                 Ok(())
             }
+            evaluate::Error::UnknownWord(word) if &word == "PROFILE_REPORT" => {
+                let report = self.report(state);
+                state.output_stream.writeln("Global Profiling Report:");
+                state.output_stream.write(&report);
+                let report = self.local_information.report(state);
+                state.output_stream.writeln("Local Profiling Report:");
+                state.output_stream.write(&report);
+                Ok(())
+            }
+            evaluate::Error::UnknownWord(word) if &word == "PROFILE_FOLDED" => {
+                let folded = self.folded_stacks(state);
+                state.output_stream.write(&folded);
+                Ok(())
+            }
+            evaluate::Error::UnknownWord(word) if &word == "PROFILE_JSON" => {
+                let json = self.json(state);
+                state.output_stream.writeln(&json);
+                Ok(())
+            }
             evaluate::Error::UnknownWord(word) if &word == "PROFILE_WORD" => {
                 match state.input_stream.next().and_then(|token| state.definitions.get_from_token(token)).map(|definition| definition.execution_token) {
                     Ok(execution_token) => {
@@ -144,4 +491,62 @@ impl<KN: kernels::Kernel> kernels::Kernel for ProfilerKernel<KN> {
             error => Err(error)
         }
     }
-}
\ No newline at end of file
+}
+#[test]
+fn call_graph_test() {
+    // keep the optimizer from changing how many instructions each definition runs, or inlining SQ into QUAD
+    let config = evaluate::config::ForthConfig { peephole_optimizations: false, automatic_inlining: false, tail_call_elimination: false, ..Default::default() };
+    let mut f = evaluate::Forth::<ProfilerKernel<kernels::DefaultKernel>>::new(config);
+    assert!(f.evaluate_string(": sq dup * ; : quad sq sq ;").is_ok());
+    assert!(f.evaluate_string("2 quad").is_ok());
+
+    let folded = f.kernel.folded_stacks(&f.state);
+    assert!(folded.contains("[interpreter];quad 5\n"));
+    assert!(folded.contains("[interpreter];quad;sq 10\n"));
+
+    let quad = f.state.definitions.get_from_str("QUAD").unwrap().execution_token;
+    let sq = f.state.definitions.get_from_str("SQ").unwrap().execution_token;
+    let statistics = f.kernel.global_information.definition_statistics();
+    assert_eq!(statistics[&quad].calls, 1);
+    assert_eq!(statistics[&quad].inclusive_instructions, 15);
+    assert_eq!(statistics[&quad].exclusive_instructions, 5);
+    assert_eq!(statistics[&sq].calls, 2);
+    assert_eq!(statistics[&sq].inclusive_instructions, 10);
+    assert_eq!(f.kernel.global_information.call_graph()[&(Some(quad), sq)].calls, 2);
+    assert_eq!(f.kernel.global_information.call_graph()[&(None, quad)].calls, 1);
+}
+
+#[test]
+fn recursion_test() {
+    let mut f = evaluate::Forth::<ProfilerKernel<kernels::DefaultKernel>>::new(Default::default());
+    assert!(f.evaluate_string(": countdown dup if 1 - countdown then ; 3 countdown").is_ok());
+
+    let json = f.kernel.json(&f.state);
+    assert!(json.contains("\"name\":\"countdown\",\"calls\":4"));
+
+    let countdown = f.state.definitions.get_from_str("COUNTDOWN").unwrap().execution_token;
+    let statistics = f.kernel.global_information.definition_statistics()[&countdown];
+    assert_eq!(statistics.inclusive_instructions, statistics.exclusive_instructions);
+}
+
+#[test]
+fn tail_call_test() {
+    // both calls are in tail position, so with the default optimizations they become jumps
+    let mut f = evaluate::Forth::<ProfilerKernel<kernels::DefaultKernel>>::new(Default::default());
+    assert!(f.evaluate_string(": countdown dup if 1 - countdown then ; : start 3 countdown ;").is_ok());
+    assert!(f.evaluate_string("start").is_ok());
+    assert_eq!(f.state.stack.pop::<crate::environment::generic_numbers::Number>(), Ok(0));
+
+    let start = f.state.definitions.get_from_str("START").unwrap().execution_token;
+    let countdown = f.state.definitions.get_from_str("COUNTDOWN").unwrap().execution_token;
+    let statistics = f.kernel.global_information.definition_statistics();
+    assert_eq!(statistics[&start].calls, 1);
+    assert_eq!(statistics[&countdown].calls, 4);
+    // start is left as soon as it jumps into countdown, which is charged for its own work
+    assert_eq!(statistics[&start].inclusive_instructions, statistics[&start].exclusive_instructions);
+    assert!(statistics[&countdown].exclusive_instructions > statistics[&start].exclusive_instructions);
+
+    let folded = f.kernel.folded_stacks(&f.state);
+    assert!(folded.contains("[interpreter];start 2\n"));
+    assert!(folded.contains("[interpreter];countdown 15\n"));
+}
@@ -0,0 +1,72 @@
+use crate::io::output_stream;
+use crate::evaluate::{self, kernels};
+
+use super::debug_operations;
+
+
+/**
+ * A kernel that writes out each instruction as it is executed, along with where it was executed from and the stack it was
+ * given.  Instructions run straight from the input stream have no address.  The trace goes to the Forth output stream,
+ * unless it is given a stream of its own, which keeps it apart from what the program prints.
+ */
+pub struct TraceKernel<'o, NK: kernels::Kernel> {
+    output_stream: Option<Box<dyn output_stream::OutputStream + 'o>>,
+    next_kernel: NK
+}
+
+impl<'o, NK: kernels::Kernel> TraceKernel<'o, NK> {
+    pub fn set_output_stream<O: output_stream::OutputStream + 'o>(&mut self, output_stream: O) {
+        self.output_stream = Some(Box::new(output_stream));
+    }
+}
+
+impl<'o, NK: kernels::Kernel> kernels::Kernel for TraceKernel<'o, NK> {
+    type NextKernel = NK;
+    fn new(state: &mut evaluate::ForthState) -> Self {
+        Self {
+            output_stream: None,
+            next_kernel: NK::new(state)
+        }
+    }
+
+    fn get_next_kernel(&mut self) -> &mut Self::NextKernel { &mut self.next_kernel }
+
+    fn evaluate(&mut self, state: &mut evaluate::ForthState) -> evaluate::ForthResult {
+        if let Some(current_instruction) = state.current_instruction() {
+            let address = state.instruction_pointer().map_or_else(|| "-".to_string(), |address| format!("{:#x}", address.as_raw()));
+            let stack = state.stack.debug_only_get_vec().iter().map(|value| value.to_number().to_string()).collect::<Vec<_>>();
+            let line = format!("{:>14} {:<32} ( {} )", address, debug_operations::stringify_execution_token(state, current_instruction), stack.join(" "));
+
+            match &mut self.output_stream {
+                Some(output_stream) => output_stream.writeln(&line),
+                None => state.output_stream.writeln(&line)
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn trace_test() {
+    use std::{rc::Rc, cell::RefCell};
+
+    // collects the trace where the test can get at it
+    struct SharedOutputStream(Rc<RefCell<String>>);
+    impl output_stream::OutputStream for SharedOutputStream {
+        fn write(&mut self, m: &str) { self.0.borrow_mut().push_str(m); }
+        fn writeln(&mut self, m: &str) { self.0.borrow_mut().push_str(&format!("{}\n", m)); }
+    }
+
+    let trace = Rc::new(RefCell::new(String::new()));
+    let mut f = evaluate::Forth::<TraceKernel<kernels::DefaultKernel>>::new(Default::default());
+    f.kernel.set_output_stream(SharedOutputStream(trace.clone()));
+    assert_eq!(f.evaluate_string(": sq dup * ; 3 sq"), Ok(()));
+    assert_eq!(f.state.stack.pop::<crate::environment::generic_numbers::Number>(), Ok(9));
+
+    let trace = trace.borrow();
+    let lines = trace.lines().collect::<Vec<_>>();
+    // the call to sq comes from the input stream, and then * runs from inside of it, after the 3 has been duplicated
+    assert!(lines.iter().any(|line| line.trim_start().starts_with("- sq (defined call") && line.ends_with("( 3 )")));
+    assert!(lines.iter().any(|line| line.trim_start().starts_with("0x") && line.contains("* (builtin)") && line.ends_with("( 3 3 )")));
+}
@@ -0,0 +1,177 @@
+use std::cell::RefCell;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::evaluate::{ForthResult, Error};
+use crate::environment::{value::Value, generic_numbers::UnsignedByte, units::{Bytes, Cells, Pages}};
+use super::memory::{MemorySegment, Memory, Address, PAGE_SIZE};
+
+
+/**
+ * Memory backed by a file on the host.  Pages are read in from the file the first time they are touched, so mapping a large
+ * file is cheap, and scanning it only costs the pages that are actually looked at.  Nothing is ever written back: when the
+ * mapping is writable it is copy-on-write, and changes only go to the pages that have been read in.
+ */
+pub struct FileMemory {
+    base: Address,
+    length: Bytes,
+    file: RefCell<fs::File>,
+    // the pages that have been read in so far, by page number
+    pages: RefCell<Vec<Option<Memory>>>,
+}
+
+impl FileMemory {
+    pub fn open<P: AsRef<Path>>(base: Address, path: P) -> Result<Self, Error> {
+        let file = fs::File::open(path).map_err(|error| Error::FileError(error.to_string()))?;
+        let length = file.metadata().map_err(|error| Error::FileError(error.to_string()))?.len() as usize;
+
+        Ok(Self {
+            base,
+            length: Bytes::bytes(length),
+            file: RefCell::new(file),
+            pages: RefCell::new((0..Bytes::bytes(length).to_pages().get_pages()).map(|_| None).collect()),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == Bytes::zero()
+    }
+
+    // the end of the last page, which is as far as the mapping reaches in the memory map
+    pub fn page_end(&self) -> Address {
+        self.base.plus(self.length.to_pages().to_bytes())
+    }
+
+    fn page_number(&self, address: Address) -> usize {
+        address.offset_from(self.base).get_bytes() / PAGE_SIZE
+    }
+
+    fn load_page(&self, page: usize) -> ForthResult {
+        if self.pages.borrow()[page].is_some() {
+            return Ok(())
+        }
+
+        // the tail of the last page, past the end of the file, reads as zeros
+        let mut bytes = Vec::with_capacity(PAGE_SIZE);
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start((page * PAGE_SIZE) as u64))
+            .and_then(|_| (&mut *file).take(PAGE_SIZE as u64).read_to_end(&mut bytes))
+            .map_err(|error| Error::FileError(error.to_string()))?;
+
+        let page_base = self.base.plus(Pages::pages(page).to_bytes());
+        let mut memory = Memory::new(page_base.as_raw()).with_num_cells(Pages::one().to_cells());
+        memory.write_bytes(page_base, &bytes)?;
+        self.pages.borrow_mut()[page] = Some(memory);
+        Ok(())
+    }
+
+    fn with_page<T>(&self, address: Address, f: impl FnOnce(&Memory) -> Result<T, Error>) -> Result<T, Error> {
+        self.check_address(address)?;
+        let page = self.page_number(address);
+        self.load_page(page)?;
+        f(self.pages.borrow()[page].as_ref().unwrap())
+    }
+
+    fn page_mut(&mut self, address: Address) -> Result<&mut Memory, Error> {
+        self.check_address(address)?;
+        let page = self.page_number(address);
+        self.load_page(page)?;
+        Ok(self.pages.get_mut()[page].as_mut().unwrap())
+    }
+
+    // splits a range up into the parts of it that are in each page, as (address, offset into the range, length)
+    fn page_pieces(&self, address: Address, len: Bytes) -> Vec<(Address, usize, usize)> {
+        let mut pieces = Vec::new();
+        let mut offset = 0;
+        while offset < len.get_bytes() {
+            let piece = address.plus(Bytes::bytes(offset));
+            let piece_len = (PAGE_SIZE - piece.as_raw() % PAGE_SIZE).min(len.get_bytes() - offset);
+            pieces.push((piece, offset, piece_len));
+            offset += piece_len;
+        }
+
+        pieces
+    }
+}
+
+impl MemorySegment for FileMemory {
+    fn get_base(&self) -> Address {
+        self.base
+    }
+
+    fn get_end(&self) -> Address {
+        self.base.plus(self.length)
+    }
+
+    fn write_value(&mut self, address: Address, value: Value) -> ForthResult {
+        self.page_mut(address)?.write_value(address, value)
+    }
+
+    fn read_value(&self, address: Address) -> Result<Value, Error> {
+        self.with_page(address, |page| page.read_value(address))
+    }
+
+    fn write_values(&mut self, address: Address, values: &[Value]) -> ForthResult {
+        self.check_range(address, Cells::cells(values.len()).to_bytes())?;
+        for (i, value) in values.iter().enumerate() {
+            self.write_value(address.plus_cell(Cells::cells(i)), *value)?;
+        }
+        Ok(())
+    }
+
+    fn read_values(&self, address: Address, len: Cells) -> Result<Vec<Value>, Error> {
+        (0..len.get_cells()).map(|i| self.read_value(address.plus_cell(Cells::cells(i)))).collect()
+    }
+
+    fn read_byte(&self, address: Address) -> Result<UnsignedByte, Error> {
+        self.with_page(address, |page| page.read_byte(address))
+    }
+
+    fn write_byte(&mut self, address: Address, byte: UnsignedByte) -> ForthResult {
+        self.page_mut(address)?.write_byte(address, byte)
+    }
+
+    fn read_bytes(&self, address: Address, len: Bytes) -> Result<Vec<UnsignedByte>, Error> {
+        self.check_range(address, len)?;
+        let mut bytes = vec![0; len.get_bytes()];
+        for (piece, offset, piece_len) in self.page_pieces(address, len) {
+            let piece_bytes = self.with_page(piece, |page| page.read_bytes(piece, Bytes::bytes(piece_len)))?;
+            bytes[offset..offset + piece_len].copy_from_slice(&piece_bytes);
+        }
+
+        Ok(bytes)
+    }
+
+    fn write_bytes(&mut self, address: Address, bytes: &[UnsignedByte]) -> ForthResult {
+        self.check_range(address, Bytes::bytes(bytes.len()))?;
+        for (piece, offset, piece_len) in self.page_pieces(address, Bytes::bytes(bytes.len())) {
+            self.page_mut(piece)?.write_bytes(piece, &bytes[offset..offset + piece_len])?;
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn file_memory_test() {
+    let path = std::env::temp_dir().join(format!("forth_file_memory_test_{}", std::process::id()));
+    let contents = (0..PAGE_SIZE * 2 + 100).map(|i| (i % 251) as UnsignedByte).collect::<Vec<_>>();
+    fs::write(&path, &contents).unwrap();
+
+    let base = Address::from_raw(Bytes::bytes(0x10000));
+    let mut file = FileMemory::open(base, &path).unwrap();
+    assert_eq!(file.get_end(), base.plus(Bytes::bytes(contents.len())));
+    assert_eq!(file.page_end(), base.plus(Bytes::bytes(PAGE_SIZE * 3)));
+
+    // reads can cross pages, and stop at the end of the file
+    assert_eq!(file.read_byte(base.plus(Bytes::bytes(PAGE_SIZE + 3))), Ok(contents[PAGE_SIZE + 3]));
+    assert_eq!(file.read_bytes(base.plus(Bytes::bytes(PAGE_SIZE - 2)), Bytes::bytes(4)), Ok(contents[PAGE_SIZE - 2..PAGE_SIZE + 2].to_vec()));
+    assert_eq!(file.read_byte(base.plus(Bytes::bytes(contents.len()))), Err(Error::InvalidAddress));
+
+    // writes only change the pages in memory
+    assert!(file.write_bytes(base.plus(Bytes::bytes(PAGE_SIZE * 2 - 1)), &[1, 2]).is_ok());
+    assert_eq!(file.read_bytes(base.plus(Bytes::bytes(PAGE_SIZE * 2 - 2)), Bytes::bytes(4)), Ok(vec![contents[PAGE_SIZE * 2 - 2], 1, 2, contents[PAGE_SIZE * 2 + 1]]));
+    fs::remove_file(&path).unwrap();
+    assert!(FileMemory::open(base, &path).is_err());
+}
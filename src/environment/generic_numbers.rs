@@ -40,6 +40,25 @@ pub trait GenericNumber: fmt::Debug + Clone + Copy + Eq + PartialEq + cmp::Ord +
      */
     fn neg(self) -> Self;
     fn abs(self) -> Self;
+
+    // formats the number in any base from 2 to 36, the way . prints it
+    fn to_string_in_radix(self, radix: u32) -> String;
+}
+
+fn format_in_radix(negative: bool, mut magnitude: u128, radix: u32) -> String {
+    let mut digits = Vec::new();
+    loop {
+        digits.push(std::char::from_digit((magnitude % radix as u128) as u32, radix).unwrap().to_ascii_uppercase());
+        magnitude /= radix as u128;
+        if magnitude == 0 {
+            break
+        }
+    }
+
+    if negative {
+        digits.push('-');
+    }
+    digits.iter().rev().collect()
 }
 
 /**
@@ -68,6 +87,8 @@ macro_rules! generic_number {
 
             fn neg(self) -> Self { -self }
             fn abs(self) -> Self { (self as $type).abs() }
+
+            fn to_string_in_radix(self, radix: u32) -> String { format_in_radix(self < 0, (self as i128).unsigned_abs(), radix) }
         }
 
         impl value::ValueVariant for $type {
@@ -113,6 +134,8 @@ macro_rules! generic_number {
 
             fn neg(self) -> Self { self }
             fn abs(self) -> Self { self }
+
+            fn to_string_in_radix(self, radix: u32) -> String { format_in_radix(false, self as u128, radix) }
         }
 
         impl value::ValueVariant for $unsigned_type {
@@ -172,14 +195,11 @@ impl AsValue for Number {
 
 impl MemoryOperations for Byte {
     fn read_number(memory_segment: &dyn memory::MemorySegment, address: memory::Address) -> Result<Self, Error> {
-        memory_segment.read_value(address).map(|value| value.to_number().to_chunks()[address.get_cell_byte()])
+        memory_segment.read_byte(address).map(|byte| byte as Byte)
     }
 
     fn write_number(self, memory_segment: &mut dyn memory::MemorySegment, address: memory::Address) -> Result<(), Error> {
-        let value = memory_segment.read_value(address)?;
-        let mut bytes = value.to_number().to_chunks();
-        bytes[address.get_cell_byte()] = self;
-        memory_segment.write_value(address, Number::from_chunks(&bytes).value())
+        memory_segment.write_byte(address, self as UnsignedByte)
     }
 }
 
@@ -1,3 +1,6 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
 use crate::evaluate::{ForthResult, Error};
 use crate::environment::{value::{Value}, generic_numbers::AsValue, units::{Bytes, Cells, Pages}};
 use super::memory::{MemorySegment, Address};
@@ -9,6 +12,9 @@ const LARGEBIN_SIZE: Cells = Bytes::bytes(0x1000).to_cells();
 const LARGEBIN_STEP: Cells = Bytes::bytes(0x80).to_cells();
 
 const PAGES_PER_RANGE: Pages = Pages::pages(16);
+// the largest single allocation.  chunks are backed by memory on the host as soon as they are allocated, so anything much
+// bigger is more likely to be a negative or garbage size than a real request
+const MAX_ALLOCATION: Bytes = Bytes::bytes(1 << 30);
 
 struct PageRange {
     // address of the first page.  must start at some multiple of 0x10000
@@ -17,14 +23,20 @@ struct PageRange {
     memory: Vec<Value>,
     // the size of all chunks within this page range, in cells
     chunk_size: Cells,
+    // how many pages the range can grow to
+    capacity: Pages,
     // a list of all available chunks that are before the empty tail area
     available: Vec<Address>,
 }
 
 impl PageRange {
     fn new(base: Address, chunk_size: Cells) -> Self {
+        Self::with_capacity(base, chunk_size, PAGES_PER_RANGE)
+    }
+
+    fn with_capacity(base: Address, chunk_size: Cells, capacity: Pages) -> Self {
         Self {
-            base, chunk_size,
+            base, chunk_size, capacity,
             memory: Vec::new(),
             available: Vec::new()
         }
@@ -35,14 +47,15 @@ impl PageRange {
     }
 
     fn is_full(&self) -> bool {
-        self.num_cells().to_pages() == PAGES_PER_RANGE && self.available.len() == 0
+        self.num_cells().to_pages() == self.capacity && self.available.len() == 0
     }
 
     fn allocate_next(&mut self) -> Result<Address, Error> {
         if self.available.len() > 0 {
             Ok(self.available.pop().unwrap())
-        } else if PAGES_PER_RANGE.to_cells() - self.num_cells() >= self.chunk_size {
+        } else if self.capacity.to_cells() - self.num_cells() >= self.chunk_size {
             let address = self.base.plus_cell(self.num_cells());
+            self.memory.try_reserve(self.chunk_size.get_cells()).map_err(|_| Error::InsufficientMemory)?;
             self.memory.resize((self.num_cells() + self.chunk_size).get_cells(), 0.value());
             Ok(address)
         } else {
@@ -54,6 +67,11 @@ impl PageRange {
         address.between(self.base, self.base.plus_cell(self.num_cells()))
     }
 
+    // whether len cells starting at the address are all in the range
+    fn contains(&self, address: Address, len: Cells) -> bool {
+        self.in_range(address) && !self.base.plus_cell(self.num_cells()).less_than(address.plus_cell(len))
+    }
+
     fn free(&mut self, address: Address) {
         if address.offset_from(self.base).to_cells() + self.chunk_size == self.num_cells() {
             self.memory.resize((self.num_cells() - self.chunk_size).get_cells(), 0.value());
@@ -63,7 +81,7 @@ impl PageRange {
     }
 
     fn cell_offset(&self, address: Address) -> Cells {
-        address.offset_from(self.base).to_cells()
+        address.offset_from(self.base).containing_cells()
     }
 
     fn write(&mut self, address: Address, value: Value) {
@@ -139,6 +157,10 @@ impl Bin {
 struct Bins {
     smallbin: Bin,
     largebin: Bin,
+    // allocations of LARGEBIN_SIZE or more, each in its own page range, by base address
+    large_chunks: BTreeMap<usize, PageRange>,
+    // runs of page range slots left behind by freed large chunks, as (first slot, number of slots)
+    free_slots: Vec<(usize, usize)>,
 }
 
 impl Bins {
@@ -146,6 +168,8 @@ impl Bins {
         Self {
             smallbin: Bin::new(Cells::zero(), SMALLBIN_SIZE, SMALLBIN_STEP),
             largebin: Bin::new(SMALLBIN_SIZE, LARGEBIN_SIZE, LARGEBIN_STEP),
+            large_chunks: BTreeMap::new(),
+            free_slots: Vec::new(),
         }
     }
 
@@ -170,10 +194,43 @@ impl Bins {
     }
 }
 
+/**
+ * A chunk that is currently allocated, along with where it was allocated from, if that is known.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Allocation {
+    pub address: Address,
+    pub size: Bytes,
+    pub word: Option<String>,
+    pub instruction_pointer: Option<Address>,
+}
+
+impl fmt::Display for Allocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let origin = match (&self.word, self.instruction_pointer) {
+            (Some(word), Some(instruction_pointer)) => format!("{} @ {:#x}", word, instruction_pointer.as_raw()),
+            (None, Some(instruction_pointer)) => format!("{:#x}", instruction_pointer.as_raw()),
+            _ => "the interpreter".to_string()
+        };
+
+        write!(f, "{:#x}: {} bytes, allocated by {}", self.address.as_raw(), self.size.get_bytes(), origin)
+    }
+}
+
+// bookkeeping done when heap diagnostics are turned on
+#[derive(Default)]
+struct Diagnostics {
+    // live allocations, by address
+    allocations: BTreeMap<usize, Allocation>,
+    // chunks that have been freed, and not handed out again since, with their sizes
+    freed: BTreeMap<usize, Bytes>,
+}
+
 pub struct Heap {
     base: Address,
     bins: Bins,
     size_lookup: Vec<Cells>,
+    diagnostics: Option<Diagnostics>,
 }
 
 impl Heap {
@@ -182,10 +239,68 @@ impl Heap {
             base: Address::from_raw(Bytes::bytes(base)), 
             bins: Bins::new(),
             size_lookup: Vec::new(),
+            diagnostics: None,
         }
     }
 
+    pub fn with_diagnostics(mut self, enabled: bool) -> Self {
+        self.diagnostics = if enabled { Some(Diagnostics::default()) } else { None };
+        self
+    }
+
+    pub fn diagnostics_enabled(&self) -> bool {
+        self.diagnostics.is_some()
+    }
+
+    /**
+     * Records where a live allocation came from.  Does nothing unless diagnostics are on.
+     */
+    pub fn set_origin(&mut self, address: Address, word: Option<String>, instruction_pointer: Option<Address>) {
+        if let Some(allocation) = self.diagnostics.as_mut().and_then(|diagnostics| diagnostics.allocations.get_mut(&address.as_raw())) {
+            allocation.word = word;
+            allocation.instruction_pointer = instruction_pointer;
+        }
+    }
+
+    /**
+     * Every allocation that hasn't been freed yet, in order of address.  Always empty unless diagnostics are on.
+     */
+    pub fn outstanding_allocations(&self) -> Vec<Allocation> {
+        self.diagnostics.as_ref()
+            .map(|diagnostics| diagnostics.allocations.values().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn report(&self) -> String {
+        if !self.diagnostics_enabled() {
+            return "heap diagnostics are off".to_string()
+        }
+
+        let allocations = self.outstanding_allocations();
+        let total: usize = allocations.iter().map(|allocation| allocation.size.get_bytes()).sum();
+        let mut report = format!("{} outstanding allocations, {} bytes", allocations.len(), total);
+        for allocation in allocations.iter() {
+            report.push_str(&format!("\n  {}", allocation));
+        }
+
+        report
+    }
+
     pub fn allocate(&mut self, size: Bytes) -> Result<Address, Error> {
+        if size > MAX_ALLOCATION {
+            return Err(Error::InsufficientMemory)
+        }
+
+        let address = if size.to_cells() < LARGEBIN_SIZE { self.allocate_chunk(size)? } else { self.allocate_large_chunk(size)? };
+        if let Some(diagnostics) = self.diagnostics.as_mut() {
+            diagnostics.freed.remove(&address.as_raw());
+            diagnostics.allocations.insert(address.as_raw(), Allocation { address, size, word: None, instruction_pointer: None });
+        }
+
+        Ok(address)
+    }
+
+    fn allocate_chunk(&mut self, size: Bytes) -> Result<Address, Error> {
         // get the adjusted size, and corresponding table
         let (size, table) = self.bins.get_bin_mut(size.to_cells()).get_page_ranges_mut(size.to_cells())?;
 
@@ -216,31 +331,118 @@ impl Heap {
         available_range.allocate_next()
     }
 
+    // large chunks get a run of whole page range slots to themselves, so that the size lookup still works
+    fn allocate_large_chunk(&mut self, size: Bytes) -> Result<Address, Error> {
+        let size = size.to_cells();
+        let num_slots = size.to_pages().get_pages().div_ceil(PAGES_PER_RANGE.get_pages());
+
+        // reuse the slots of a freed large chunk if any are big enough
+        let first_slot = match self.bins.free_slots.iter().position(|(_, free)| *free >= num_slots) {
+            Some(i) => {
+                let (first_slot, free) = self.bins.free_slots[i];
+                if free == num_slots {
+                    self.bins.free_slots.swap_remove(i);
+                } else {
+                    self.bins.free_slots[i] = (first_slot + num_slots, free - num_slots);
+                }
+                first_slot
+            },
+            None => {
+                self.size_lookup.resize(self.size_lookup.len() + num_slots, size);
+                self.size_lookup.len() - num_slots
+            }
+        };
+
+        let base = self.base.plus(PAGES_PER_RANGE.to_bytes() * first_slot);
+        let mut range = PageRange::with_capacity(base, size, PAGES_PER_RANGE * num_slots);
+        let address = match range.allocate_next() {
+            Ok(address) => address,
+            Err(error) => {
+                // the slots go back, still marked with the size, which doesn't matter until they are used again
+                self.bins.free_slots.push((first_slot, num_slots));
+                return Err(error)
+            }
+        };
+        self.size_lookup[first_slot..first_slot + num_slots].fill(size);
+        self.bins.large_chunks.insert(base.as_raw(), range);
+
+        Ok(address)
+    }
+
     pub fn free(&mut self, address: Address) -> ForthResult {
-        self.get_containing_range_mut(address).map(|range| range.free(address))
+        if let Some(diagnostics) = self.diagnostics.as_mut() {
+            match diagnostics.allocations.remove(&address.as_raw()) {
+                Some(allocation) => diagnostics.freed.insert(address.as_raw(), allocation.size),
+                None => return Err(if diagnostics.freed.contains_key(&address.as_raw()) { Error::DoubleFree } else { Error::InvalidAddress })
+            };
+        }
+
+        let size = self.lookup_size(address)?;
+        if size < LARGEBIN_SIZE {
+            return self.get_containing_range_mut(address).map(|range| range.free(address))
+        }
+
+        match self.bins.large_chunks.get(&address.as_raw()) {
+            Some(range) => {
+                let first_slot = self.slot(address);
+                let num_slots = range.capacity / PAGES_PER_RANGE;
+                self.bins.large_chunks.remove(&address.as_raw());
+                self.bins.free_slots.push((first_slot, num_slots));
+                Ok(())
+            },
+            None => Err(Error::InvalidAddress)
+        }
     }
 
+    /**
+     * Changes the size of an allocation, moving it (and copying its contents) when it no longer fits where it is.
+     */
     pub fn resize(&mut self, address: Address, size: Bytes) -> Result<Address, Error> {
-        let old_size = self.lookup_size(address)?;
-        if old_size < size.to_cells() {
-            self.free(address)?;
-            self.allocate(size)
-        } else {
-            Ok(address)
+        self.check_not_freed(address)?;
+        if size > MAX_ALLOCATION {
+            return Err(Error::InsufficientMemory)
+        }
+
+        let old_size = self.get_containing_range(address)?.chunk_size;
+        let new_size = size.to_cells();
+
+        // a large chunk stays put unless it shrinks down to the bins, so that its pages can be given back
+        if new_size <= old_size && (old_size < LARGEBIN_SIZE || new_size >= LARGEBIN_SIZE) {
+            if let Some(allocation) = self.diagnostics.as_mut().and_then(|diagnostics| diagnostics.allocations.get_mut(&address.as_raw())) {
+                allocation.size = size;
+            }
+            return Ok(address)
         }
+
+        let contents = self.read_values(address, std::cmp::min(old_size, new_size))?;
+        let new_address = self.allocate(size)?;
+        self.write_values(new_address, &contents)?;
+        self.free(address)?;
+
+        Ok(new_address)
+    }
+
+    fn slot(&self, address: Address) -> usize {
+        address.offset_from(self.base) / PAGES_PER_RANGE.to_bytes()
     }
 
     fn lookup_size(&self, address: Address) -> Result<Cells, Error> {
-        let index = address.offset_from(self.base).to_pages() / PAGES_PER_RANGE;
-        if index >= self.size_lookup.len() {
+        if address.less_than(self.base) || self.slot(address) >= self.size_lookup.len() {
             return Err(Error::InvalidAddress)
         }
 
-        Ok(self.size_lookup[index])
+        Ok(self.size_lookup[self.slot(address)])
     }
 
     fn get_containing_range_mut(&mut self, address: Address) -> Result<&mut PageRange, Error> {
         let size = self.lookup_size(address)?;
+        if size >= LARGEBIN_SIZE {
+            return self.bins.large_chunks.range_mut(..=address.as_raw()).next_back()
+                .map(|(_, range)| range)
+                .filter(|range| range.in_range(address))
+                .ok_or(Error::InvalidAddress)
+        }
+
         let (_, table) = self.bins.get_bin_mut(size).get_page_ranges_mut(size)?;
         
         for range in table.iter_mut().rev() {
@@ -254,6 +456,13 @@ impl Heap {
 
     fn get_containing_range(&self, address: Address) -> Result<&PageRange, Error> {
         let size = self.lookup_size(address)?;
+        if size >= LARGEBIN_SIZE {
+            return self.bins.large_chunks.range(..=address.as_raw()).next_back()
+                .map(|(_, range)| range)
+                .filter(|range| range.in_range(address))
+                .ok_or(Error::InvalidAddress)
+        }
+
         let (_, table) = self.bins.get_bin(size).get_page_ranges(size)?;
 
         for range in table.iter().rev() {
@@ -264,6 +473,20 @@ impl Heap {
 
         Err(Error::InvalidAddress)
     }
+
+    fn check_not_freed(&self, address: Address) -> ForthResult {
+        let diagnostics = match &self.diagnostics {
+            Some(diagnostics) => diagnostics,
+            None => return Ok(())
+        };
+
+        // the closest freed chunk at or before the address, as long as nothing has been allocated over it since
+        match diagnostics.freed.range(..=address.as_raw()).next_back() {
+            Some((&chunk, &size)) if address.less_than(Address::from_raw(Bytes::bytes(chunk)).plus(size))
+                && diagnostics.allocations.range(chunk..=address.as_raw()).next().is_none() => Err(Error::UseAfterFree),
+            _ => Ok(())
+        }
+    }
 }
 
 impl MemorySegment for Heap {
@@ -276,20 +499,23 @@ impl MemorySegment for Heap {
     }
 
     fn write_value(&mut self, address: Address, value: Value) -> Result<(), Error> {        
+        self.check_not_freed(address)?;
         self.get_containing_range_mut(address).map(|range| {            
             range.write(address, value)
         })
     }
 
     fn read_value(&self, address: Address) -> Result<Value, Error> {
+        self.check_not_freed(address)?;
         self.get_containing_range(address).map(|range| {
             range.read(address)
         })
     }
 
     fn write_values(&mut self, address: Address, values: &[Value]) -> ForthResult {
+        self.check_not_freed(address)?;
         let range = self.get_containing_range_mut(address)?;
-        if range.in_range(address.plus_cell(Cells::cells(values.len()))) {
+        if range.contains(address, Cells::cells(values.len())) {
             Ok(range.write_values(address, values))
         } else {
             Err(Error::InvalidAddress)
@@ -297,8 +523,9 @@ impl MemorySegment for Heap {
     }
 
     fn read_values(&self, address: Address, len: Cells) -> Result<Vec<Value>, Error> {
+        self.check_not_freed(address)?;
         let range = self.get_containing_range(address)?;
-        if range.in_range(address.plus_cell(len)) {
+        if range.contains(address, len) {
             Ok(range.read_values(address, len.get_cells()))
         } else {
             Err(Error::InvalidAddress)
@@ -388,4 +615,84 @@ fn read_write_test() {
     assert_eq!(state.read::<u64>(allocations[5].plus_cell(Cells::cells(10))), Ok(0xabcd));
 
     println!("heap: {}", state.heap.to_string());
-}
\ No newline at end of file
+}
+
+#[test]
+fn diagnostics_test() {
+    use crate::evaluate::{config::ForthConfig, kernels::DefaultKernel, Forth};
+
+    let mut f = Forth::<DefaultKernel>::new(ForthConfig { heap_diagnostics: true, ..Default::default() })
+        .with_output_stream(crate::io::output_stream::BufferedOutputStream::new());
+    assert!(f.evaluate_string(": make 64 allocate drop ;  make constant a  make constant b  24 allocate drop constant c  a free drop").is_ok());
+
+    let outstanding = f.state.heap.outstanding_allocations();
+    assert_eq!(outstanding.iter().map(|allocation| allocation.word.clone()).collect::<Vec<_>>(), vec![Some("make".to_string()), None]);
+    assert!(outstanding[0].instruction_pointer.is_some());
+
+    assert!(f.evaluate_string("heap-report").is_ok());
+    assert!(f.state.output_stream.consume().starts_with("2 outstanding allocations, 88 bytes"));
+
+    assert_eq!(f.evaluate_string("a free"), Err(Error::DoubleFree));
+    assert_eq!(f.evaluate_string("a @"), Err(Error::UseAfterFree));
+    assert_eq!(f.evaluate_string("a 128 resize"), Err(Error::UseAfterFree));
+    assert_eq!(f.evaluate_string("b 8 + @ drop  b free"), Ok(()));
+}
+
+#[test]
+fn large_allocations_test() {
+    let mut state = crate::evaluate::ForthState::new(Default::default());
+    let buffer_1 = state.heap.allocate(Bytes::bytes(0x2ff000)).unwrap();
+    let buffer_2 = state.heap.allocate(Bytes::bytes(0x1000)).unwrap();
+    let small = state.heap.allocate(Bytes::bytes(50)).unwrap();
+
+    // the whole buffer is usable, and nothing past it is
+    let last_cell = buffer_1.plus(Bytes::bytes(0x2ff000 - 8));
+    assert!(state.write::<u64>(buffer_1, 1).is_ok());
+    assert!(state.write::<u64>(last_cell, 2).is_ok());
+    assert_eq!(state.write::<u64>(last_cell.plus_cell(Cells::one()), 3), Err(Error::InvalidAddress));
+    assert!(state.fill_bytes(buffer_2, Bytes::bytes(0x1000), 0xab).is_ok());
+    assert_eq!(state.read::<u64>(buffer_1), Ok(1));
+    assert_eq!(state.read::<u64>(last_cell), Ok(2));
+    assert_eq!(state.read::<u64>(buffer_2.plus(Bytes::bytes(0xff8))), Ok(0xabababababababab));
+    assert!(state.write::<u64>(small, 4).is_ok());
+
+    // freed pages get reused by the next large chunk that fits
+    assert!(state.heap.free(buffer_1).is_ok());
+    assert_eq!(state.read::<u64>(buffer_1), Err(Error::InvalidAddress));
+    assert_eq!(state.heap.free(buffer_1.plus_cell(Cells::one())), Err(Error::InvalidAddress));
+    let buffer_3 = state.heap.allocate(Bytes::bytes(0x100000)).unwrap();
+    assert_eq!(buffer_3, buffer_1);
+    assert_eq!(state.read::<u64>(buffer_3), Ok(0));
+    assert_eq!(state.read::<u64>(small), Ok(4));
+
+    // sizes too big to be real fail, rather than taking the host down trying to back them, and leave the heap as it was
+    assert_eq!(state.heap.allocate(Bytes::bytes(100000000000)), Err(Error::InsufficientMemory));
+    assert_eq!(state.heap.allocate(Bytes::bytes(usize::MAX)), Err(Error::InsufficientMemory));
+    assert_eq!(state.heap.resize(small, Bytes::bytes(usize::MAX)), Err(Error::InsufficientMemory));
+    assert_eq!(state.read::<u64>(small), Ok(4));
+}
+
+#[test]
+fn resize_test() {
+    let mut state = crate::evaluate::ForthState::new(Default::default());
+    let address = state.heap.allocate(Bytes::bytes(50)).unwrap();
+    assert!(state.write::<u64>(address, 7).is_ok());
+    assert!(state.write::<u64>(address.plus_cell(Cells::cells(6)), 8).is_ok());
+
+    // staying within the chunk doesn't move it
+    assert_eq!(state.heap.resize(address, Bytes::bytes(60)), Ok(address));
+
+    // growing moves it through the largebin and into a large chunk, keeping the contents
+    let medium = state.heap.resize(address, Bytes::bytes(0x800)).unwrap();
+    assert_ne!(medium, address);
+    let large = state.heap.resize(medium, Bytes::bytes(0x200000)).unwrap();
+    assert_eq!(state.read::<u64>(large), Ok(7));
+    assert_eq!(state.read::<u64>(large.plus_cell(Cells::cells(6))), Ok(8));
+    assert!(state.write::<u64>(large.plus(Bytes::bytes(0x1ffff8)), 9).is_ok());
+
+    // shrinking a large chunk back down moves it into the bins, truncating it
+    let small = state.heap.resize(large, Bytes::bytes(16)).unwrap();
+    assert_eq!(state.read::<u64>(small.plus_cell(Cells::one())), Ok(0));
+    assert_eq!(state.read::<u64>(small), Ok(7));
+    assert_eq!(state.read::<u64>(large), Err(Error::InvalidAddress));
+}
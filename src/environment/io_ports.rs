@@ -0,0 +1,76 @@
+use std::cell::RefCell;
+
+use crate::evaluate::{ForthResult, Error};
+use crate::environment::{value::Value, generic_numbers::{Number, AsValue}, units::Cells};
+use super::memory::{MemorySegment, Address};
+
+
+type ReadCallback = Box<dyn FnMut(Cells) -> Number>;
+type WriteCallback = Box<dyn FnMut(Cells, Number)>;
+
+/**
+ * A range of cells whose reads and writes are handled by the host, for memory-mapped I/O.  Like the state registers in the
+ * internal state memory, nothing is stored: each access calls back into the host with the offset of the cell from the base,
+ * so a store to a port can set off device logic and a fetch can return live status.  Byte accesses go through the whole cell,
+ * so a C! reads the port before writing it.
+ */
+pub struct IoPorts {
+    base: Address,
+    len: Cells,
+    // reads only get shared access to the segment, but devices often change when they are read
+    read: RefCell<ReadCallback>,
+    write: WriteCallback,
+}
+
+impl IoPorts {
+    /**
+     * Ports that read as zero, and ignore writes, until callbacks are given.
+     */
+    pub fn new(base: Address, len: Cells) -> Self {
+        Self { base, len, read: RefCell::new(Box::new(|_| 0)), write: Box::new(|_, _| ()) }
+    }
+
+    pub fn on_read<F: FnMut(Cells) -> Number + 'static>(mut self, read: F) -> Self {
+        self.read = RefCell::new(Box::new(read));
+        self
+    }
+
+    pub fn on_write<F: FnMut(Cells, Number) + 'static>(mut self, write: F) -> Self {
+        self.write = Box::new(write);
+        self
+    }
+}
+
+impl MemorySegment for IoPorts {
+    fn get_base(&self) -> Address {
+        self.base
+    }
+
+    fn get_end(&self) -> Address {
+        self.base.plus_cell(self.len)
+    }
+
+    fn write_value(&mut self, address: Address, value: Value) -> ForthResult {
+        let port = self.cell_offset(address)?;
+        (self.write)(port, value.to_number());
+        Ok(())
+    }
+
+    fn read_value(&self, address: Address) -> Result<Value, Error> {
+        let port = self.cell_offset(address)?;
+        Ok((self.read.borrow_mut())(port).value())
+    }
+
+    fn write_values(&mut self, address: Address, values: &[Value]) -> ForthResult {
+        self.check_range(address, Cells::cells(values.len()).to_bytes())?;
+        for (i, value) in values.iter().enumerate() {
+            self.write_value(address.plus_cell(Cells::cells(i)), *value)?;
+        }
+        Ok(())
+    }
+
+    fn read_values(&self, address: Address, len: Cells) -> Result<Vec<Value>, Error> {
+        self.check_range(address, len.to_bytes())?;
+        (0..len.get_cells()).map(|i| self.read_value(address.plus_cell(Cells::cells(i)))).collect()
+    }
+}
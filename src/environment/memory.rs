@@ -1,10 +1,12 @@
 use std::mem;
+use std::cell::Cell;
 
 use crate::evaluate::{ForthState, Error, ForthResult};
 use super::value::{self, ValueVariant};
 use super::generic_numbers;
-use super::generic_numbers::{ConvertOperations, AsValue};
+use super::generic_numbers::{ConvertOperations, AsValue, UnsignedByte};
 use crate::environment::{stack, memory, units::{Bytes, Cells}};
+use crate::evaluate::definition::ExecutionToken;
 
 
 pub const PAGE_SIZE: usize = 0x1000;
@@ -147,6 +149,21 @@ impl MemoryPermissions {
         Self::with(true, true, true)
     }
 
+    pub fn none() -> Self {
+        Self::with(false, false, false)
+    }
+
+    /**
+     * Permissions as the bits read = 1, write = 2 and execute = 4, which is how Forth code passes them around.
+     */
+    pub fn from_bits(bits: usize) -> Self {
+        Self::with(bits & 1 != 0, bits & 2 != 0, bits & 4 != 0)
+    }
+
+    pub fn to_bits(self) -> usize {
+        (self.read as usize) | (self.write as usize) << 1 | (self.execute as usize) << 2
+    }
+
     pub fn with_write(&self) -> Self {
         Self::with(self.read, true, self.execute)
     }
@@ -190,6 +207,13 @@ pub enum MappingType {
     },
     Anonymous {
         index: usize,
+    },
+    File {
+        index: usize,
+    },
+    // memory provided by whatever is embedding the interpreter
+    Host {
+        index: usize,
     }
 }
 
@@ -214,6 +238,14 @@ impl MemoryMapping {
         Self::new(base, permissions, MappingType::Anonymous { index })
     }
 
+    pub fn file(base: Address, permissions: MemoryPermissions, index: usize) -> Self {
+        Self::new(base, permissions, MappingType::File { index })
+    }
+
+    pub fn host(base: Address, permissions: MemoryPermissions, index: usize) -> Self {
+        Self::new(base, permissions, MappingType::Host { index })
+    }
+
     pub fn empty(base: Address, permissions: MemoryPermissions) -> Self {
         Self::new(base, permissions, MappingType::Empty)
     }
@@ -234,15 +266,15 @@ impl MemoryMapping {
 
 // contains a vector of memory mappings sorted by start
 pub struct MemoryMap {
-    // TODO implement later for speedup, test speedup
-    // cache: MemoryMapping, 
+    // index of the entry that the last lookup found.  most accesses land in the same mapping as the one before, especially in loops
+    cache: Cell<Option<usize>>,
     entries: Vec<MemoryMapping>
 }
 
 impl MemoryMap {
     pub fn new(mut entries: Vec<MemoryMapping>) -> Self {
         entries.sort_by_key(|a| a.base.as_raw());
-        Self{ entries }
+        Self{ cache: Cell::new(None), entries }
     }
 
     pub fn get_entries<'a>(&'a self) -> &'a Vec<MemoryMapping> {
@@ -253,30 +285,76 @@ impl MemoryMap {
         self.entries.len()
     }
 
+    // whether the address is between the entry and the next one
+    fn entry_contains(&self, index: usize, address: Address) -> bool {
+        !address.less_than(self.entries[index].base) && self.entries.get(index + 1).map(|next| address.less_than(next.base)).unwrap_or(true)
+    }
+
     pub fn get(&self, address: Address) -> Result<MemoryMapping, Error> {
-        if address.less_than(self.entries[0].base) {
-            return Err(Error::InvalidAddress)
+        if let Some(index) = self.cache.get() {
+            if self.entry_contains(index, address) {
+                return Ok(self.entries[index])
+            }
         }
 
-        // binary search for the correct section.  a match means the address is between the current and the next entry
-        let mut start = 0;
-        let mut end = self.entry_count();
-        loop {
-            let middle = start + (end - start) / 2;
-            if address.less_than(self.entries[middle].base) {
-                end = middle - 1;
-            } else if middle == self.entry_count() - 1 || address.less_than(self.entries[middle + 1].base) {
-                break Ok(self.entries[middle])
-            } else {
-                start = middle + 1
+        // binary search for the last entry starting at or before the address
+        match self.entries.partition_point(|entry| !address.less_than(entry.base)) {
+            0 => Err(Error::InvalidAddress),
+            end => {
+                self.cache.set(Some(end - 1));
+                Ok(self.entries[end - 1])
             }
         }
     }
 
+    // the entries with a base between start (inclusive) and end (exclusive)
+    pub fn entries_between(&self, start: Address, end: Address) -> &[MemoryMapping] {
+        let first = self.entries.partition_point(|entry| entry.base.less_than(start));
+        let last = self.entries.partition_point(|entry| entry.base.less_than(end));
+        &self.entries[first..last.max(first)]
+    }
+
+    /**
+     * Makes sure that an entry starts exactly at the address, by splitting the entry containing it in two.
+     */
+    pub fn split(&mut self, address: Address) -> ForthResult {
+        let mapping = self.get(address)?;
+        if mapping.base == address {
+            Ok(())
+        } else {
+            self.add(MemoryMapping { base: address, ..mapping })
+        }
+    }
+
+    pub fn set_permissions(&mut self, start: Address, end: Address, permissions: MemoryPermissions) {
+        self.entries.iter_mut()
+            .filter(|entry| entry.base.between(start, end))
+            .for_each(|entry| entry.permissions = permissions);
+    }
+
+    /**
+     * Replaces every entry with a base between start and end with a single mapping, which should start at start.
+     */
+    pub fn replace(&mut self, start: Address, end: Address, mapping: MemoryMapping) -> ForthResult {
+        self.cache.set(None);
+        self.entries.retain(|entry| !entry.base.between(start, end));
+        self.add(mapping)
+    }
+
     pub fn add(&mut self, mapping: MemoryMapping) -> ForthResult {
         match self.entries.binary_search_by_key(&mapping.base.as_raw(), |a| a.base.as_raw()) {
+            // empty entries are just holes left behind by unmapping, and can be mapped over
+            Ok(i) if matches!(self.entries[i].mapping_type, MappingType::Empty) => {
+                self.entries[i] = mapping;
+                Ok(())
+            },
             Ok(_) => Err(Error::InvalidAddress),
-            Err(i) => Ok(self.entries.insert(i, mapping)),
+            Err(i) => {
+                // the indices after the new entry have shifted
+                self.cache.set(None);
+                self.entries.insert(i, mapping);
+                Ok(())
+            }
         }
     }
 }
@@ -291,6 +369,13 @@ pub trait MemorySegment {
             Err(Error::InvalidAddress)
         }
     }
+    fn check_range(&self, address: Address, len: Bytes) -> ForthResult {
+        if len == Bytes::zero() {
+            Ok(())
+        } else {
+            self.check_address(address).and(self.check_address(address.plus(len - Bytes::one())))
+        }
+    }
     fn cell_offset(&self, address: Address) -> Result<Cells, Error> {
         self.check_address(address).map(|_| address.offset_from(self.get_base()).containing_cells())
     }
@@ -300,6 +385,43 @@ pub trait MemorySegment {
     fn write_values(&mut self, address: Address, values: &[value::Value]) -> ForthResult;
     fn read_values(&self, address: Address, len: Cells) -> Result<Vec<value::Value>, Error>;
 
+    /*
+     * Byte access.  By default, these read and rewrite the whole cell the byte is in, which is all that segments storing
+     * values can do.  Segments with a byte representation should override them.
+     */
+    fn read_byte(&self, address: Address) -> Result<UnsignedByte, Error> {
+        self.read_value(address).map(|value| value.to_number().to_chunks()[address.get_cell_byte()] as UnsignedByte)
+    }
+
+    fn write_byte(&mut self, address: Address, byte: UnsignedByte) -> ForthResult {
+        let mut bytes: Vec<generic_numbers::Byte> = self.read_value(address)?.to_number().to_chunks();
+        bytes[address.get_cell_byte()] = byte as generic_numbers::Byte;
+        self.write_value(address, generic_numbers::Number::from_chunks(&bytes).value())
+    }
+
+    fn read_bytes(&self, address: Address, len: Bytes) -> Result<Vec<UnsignedByte>, Error> {
+        (0..len.get_bytes()).map(|i| self.read_byte(address.plus(Bytes::bytes(i)))).collect()
+    }
+
+    fn write_bytes(&mut self, address: Address, bytes: &[UnsignedByte]) -> ForthResult {
+        // check the whole range first, so a failed write doesn't leave half of it behind
+        self.check_range(address, Bytes::bytes(bytes.len()))?;
+        for (i, byte) in bytes.iter().enumerate() {
+            self.write_byte(address.plus(Bytes::bytes(i)), *byte)?;
+        }
+        Ok(())
+    }
+
+    fn fill_bytes(&mut self, address: Address, len: Bytes, byte: UnsignedByte) -> ForthResult {
+        self.write_bytes(address, &vec![byte; len.get_bytes()])
+    }
+
+    // copies as if through a temporary buffer, so the ranges can overlap
+    fn copy_bytes(&mut self, source: Address, destination: Address, len: Bytes) -> ForthResult {
+        let bytes = self.read_bytes(source, len)?;
+        self.write_bytes(destination, &bytes)
+    }
+
     fn write<T: value::ValueVariant>(&mut self, address: Address, value: T) -> ForthResult where Self: Sized {
         value.write_to_memory(self, address)
     }
@@ -316,12 +438,18 @@ pub struct Memory {
     representation of the memory, and it is allocated lazily.
      */
     length: Cells,
-    memory: Vec<value::Value>
+    // the bytes of every cell, in little endian order, so byte and cell accesses see the same thing
+    memory: Vec<UnsignedByte>,
+    /*
+    Execution tokens can't be rebuilt from their bytes, so the cells holding one keep it here, at the same index, while
+    the bytes hold its offset.  Writing over any byte of the cell turns it back into a plain number.
+     */
+    execution_tokens: Vec<Option<ExecutionToken>>,
 }
 
 impl Memory {
     pub fn new(base: usize) -> Self {
-        Self { base: Address::from_raw(Bytes::bytes(base)), length: Cells::zero(), memory: Vec::new() }
+        Self { base: Address::from_raw(Bytes::bytes(base)), length: Cells::zero(), memory: Vec::new(), execution_tokens: Vec::new() }
     }
 
     pub fn with_num_cells(mut self, num_cells: Cells) -> Self {
@@ -337,12 +465,16 @@ impl Memory {
         self.length += amount;
     }
 
+    // gives back everything from the address up, which has to be a cell in the segment
+    pub fn truncate(&mut self, top: Address) {
+        self.length = top.offset_from(self.base).to_cells();
+        self.memory.truncate(self.length.get_cells() * CELL_SIZE);
+        self.execution_tokens.truncate(self.length.get_cells());
+    }
+
     pub fn push_value(&mut self, value: value::Value) {
-        if Cells::cells(self.memory.len()) < self.length {
-            self.memory.resize(self.length.get_cells(), 0.value());
-        }
+        self.set_cell(self.length.get_cells(), value);
         self.length += Cells::one();
-        self.memory.push(value);
     }
     
     pub fn push<T: value::ValueVariant>(&mut self, value: T) {
@@ -355,11 +487,49 @@ impl Memory {
         }
     }
 
-    pub fn debug_only_get_vec<'a>(&'a self) -> &'a Vec<value::Value> {
-        &self.memory
+    // make sure the bytes up to end are backed by the vector, always allocating whole cells
+    fn allocate(&mut self, end: usize) {
+        let cells = Bytes::bytes(end).to_cells().get_cells();
+        if cells > self.execution_tokens.len() {
+            self.memory.resize(cells * CELL_SIZE, 0);
+            self.execution_tokens.resize(cells, None);
+        }
+    }
+
+    fn byte_offset(&self, address: Address) -> Result<usize, Error> {
+        self.check_address(address).map(|_| address.offset_from(self.base).get_bytes())
+    }
+
+    fn set_cell(&mut self, index: usize, value: value::Value) {
+        let start = index * CELL_SIZE;
+        self.allocate(start + CELL_SIZE);
+        self.memory[start..start + CELL_SIZE].copy_from_slice(&value.to_number().to_le_bytes());
+
+        self.execution_tokens[index] = match value {
+            value::Value::ExecutionToken(execution_token) => Some(execution_token),
+            value::Value::Number(_) => None
+        };
     }
 
-    // read chunk (address and length) and write chunk (address and length), time it vs. manual loop one at a time
+    fn get_cell(&self, index: usize) -> value::Value {
+        if let Some(Some(execution_token)) = self.execution_tokens.get(index) {
+            return execution_token.value()
+        }
+
+        let start = index * CELL_SIZE;
+        let mut bytes = [0; CELL_SIZE];
+        if let Some(cell) = self.memory.get(start..start + CELL_SIZE) {
+            bytes.copy_from_slice(cell);
+        }
+        generic_numbers::Number::from_le_bytes(bytes).value()
+    }
+
+    // drop the execution tokens of every cell that overlaps the byte range
+    fn forget_execution_tokens(&mut self, start: usize, end: usize) {
+        if start < end {
+            self.execution_tokens[start / CELL_SIZE..end.div_ceil(CELL_SIZE)].fill(None);
+        }
+    }
 }
 
 impl MemorySegment for Memory {
@@ -373,66 +543,105 @@ impl MemorySegment for Memory {
 
     fn write_value(&mut self, address: Address, value: value::Value) -> ForthResult {
         let index = self.cell_offset(address)?.get_cells();
-        if index >= self.memory.len() {
-            self.memory.resize(index + 1, 0.value())
-        }
-        self.memory[index] = value;
+        self.set_cell(index, value);
         Ok(())
     }
 
     fn read_value(&self, address: Address) -> Result<value::Value, Error> {
-        let index = self.cell_offset(address)?.get_cells();
-        Ok(if index >= self.memory.len() {
-            0.value()
-        } else {
-            self.memory[index]
-        })
+        self.cell_offset(address).map(|index| self.get_cell(index.get_cells()))
     }
 
     fn write_values(&mut self, address: Address, values: &[value::Value]) -> ForthResult {
-        if values.len() == 0 {
+        if values.is_empty() {
             return Ok(())
         }
 
-        // get the start and end indexes
         let start = self.cell_offset(address)?.get_cells();
-        let end = self.cell_offset(address.plus_cell(Cells::cells(values.len() - 1)))?.get_cells();
-
-        // make sure there is enough actually allocated space
-        if end >= self.memory.len() {
-            self.memory.resize(end + 1, 0.value())
+        self.cell_offset(address.plus_cell(Cells::cells(values.len() - 1)))?;
+        for (i, value) in values.iter().enumerate() {
+            self.set_cell(start + i, *value);
         }
 
-        // copy the given values into the slice
-        let slice = &mut self.memory[start..end + 1];
-        slice.copy_from_slice(values);
-
-        Ok(())    
+        Ok(())
     }
 
     fn read_values(&self, address: Address, len: Cells) -> Result<Vec<value::Value>, Error> {
-        let mut num_cells = len.get_cells();
-        if num_cells == 0 { 
+        if len == Cells::zero() { 
             return Ok(Vec::new())
         }
 
-        // get the start and end indexes
         let start = self.cell_offset(address)?.get_cells();
-        let mut end = self.cell_offset(address.plus_cell(len - Cells::one()))?.get_cells();
+        self.cell_offset(address.plus_cell(len - Cells::one()))?;
+        Ok((start..start + len.get_cells()).map(|index| self.get_cell(index)).collect())
+    }
 
-        // allocate the results vector
-        let mut results = vec![0.value(); num_cells];
+    fn read_byte(&self, address: Address) -> Result<UnsignedByte, Error> {
+        self.byte_offset(address).map(|offset| self.memory.get(offset).copied().unwrap_or(0))
+    }
 
-        // if the range extends beyond the length of allocated space, only copy what is available, and leave the rest as 0
-        if end >= self.memory.len() {
-            end = self.memory.len() - 1;
-            num_cells = end + 1 - start;
-        }
+    fn write_byte(&mut self, address: Address, byte: UnsignedByte) -> ForthResult {
+        let offset = self.byte_offset(address)?;
+        self.allocate(offset + 1);
+        self.memory[offset] = byte;
+        self.forget_execution_tokens(offset, offset + 1);
+        Ok(())
+    }
+
+    fn read_bytes(&self, address: Address, len: Bytes) -> Result<Vec<UnsignedByte>, Error> {
+        self.check_range(address, len)?;
+        let start = address.offset_from(self.base).get_bytes();
+        let end = start + len.get_bytes();
+
+        // anything past the allocated part of the memory is left as 0
+        let mut bytes = vec![0; len.get_bytes()];
+        let available = self.memory.len().clamp(start, end);
+        bytes[..available - start].copy_from_slice(&self.memory[start..available]);
+        Ok(bytes)
+    }
+
+    fn write_bytes(&mut self, address: Address, bytes: &[UnsignedByte]) -> ForthResult {
+        self.check_range(address, Bytes::bytes(bytes.len()))?;
+        let start = address.offset_from(self.base).get_bytes();
+        let end = start + bytes.len();
+
+        self.allocate(end);
+        self.memory[start..end].copy_from_slice(bytes);
+        self.forget_execution_tokens(start, end);
+        Ok(())
+    }
+
+    fn fill_bytes(&mut self, address: Address, len: Bytes, byte: UnsignedByte) -> ForthResult {
+        self.check_range(address, len)?;
+        let start = address.offset_from(self.base).get_bytes();
+        let end = start + len.get_bytes();
+
+        self.allocate(end);
+        self.memory[start..end].fill(byte);
+        self.forget_execution_tokens(start, end);
+        Ok(())
+    }
 
-        let slice = &mut results[..num_cells];
-        slice.copy_from_slice(&self.memory[start..end + 1]);
+    fn copy_bytes(&mut self, source: Address, destination: Address, len: Bytes) -> ForthResult {
+        self.check_range(source, len)?;
+        self.check_range(destination, len)?;
+        let (from, to) = (source.offset_from(self.base).get_bytes(), destination.offset_from(self.base).get_bytes());
+        let len = len.get_bytes();
 
-        Ok(results)
+        self.allocate(from.max(to) + len);
+        self.memory.copy_within(from..from + len, to);
+
+        // execution tokens come along when whole cells are copied to cell boundaries, and there may not be any whole cells
+        let (first_cell, end_cell) = (from.div_ceil(CELL_SIZE), (from + len) / CELL_SIZE);
+        let carried = if from % CELL_SIZE == to % CELL_SIZE && first_cell < end_cell {
+            self.execution_tokens[first_cell..end_cell].to_vec()
+        } else {
+            Vec::new()
+        };
+
+        self.forget_execution_tokens(to, to + len);
+        let destination = to.div_ceil(CELL_SIZE);
+        self.execution_tokens[destination..destination + carried.len()].copy_from_slice(&carried);
+        Ok(())
     }
 }
 
@@ -479,4 +688,47 @@ fn memory_map_ordering_test() {
 
     assert!(memory_map.get(Address::from_raw(Bytes::bytes(50))).is_ok());
     assert_eq!(memory_map.get(Address::from_raw(Bytes::bytes(130))).unwrap().base, Address::from_raw(Bytes::bytes(128)));
-}
\ No newline at end of file
+}
+
+#[test]
+fn memory_map_cache_test() {
+    let mut memory_map = MemoryMap::new(vec![
+        MemoryMapping::empty(Address::from_raw(Bytes::bytes(32)), MemoryPermissions::readonly()),
+        MemoryMapping::empty(Address::from_raw(Bytes::bytes(1024)), MemoryPermissions::readonly())
+    ]);
+
+    // repeated lookups in the same mapping hit the cache, and lookups elsewhere still find the right mapping
+    assert_eq!(memory_map.get(Address::from_raw(Bytes::bytes(40))).unwrap().base, Address::from_raw(Bytes::bytes(32)));
+    assert_eq!(memory_map.get(Address::from_raw(Bytes::bytes(900))).unwrap().base, Address::from_raw(Bytes::bytes(32)));
+    assert_eq!(memory_map.get(Address::from_raw(Bytes::bytes(2048))).unwrap().base, Address::from_raw(Bytes::bytes(1024)));
+    assert!(memory_map.get(Address::from_raw(Bytes::bytes(8))).is_err());
+
+    // adding a mapping in between shifts the cached index
+    assert_eq!(memory_map.get(Address::from_raw(Bytes::bytes(900))).unwrap().base, Address::from_raw(Bytes::bytes(32)));
+    assert!(memory_map.add(MemoryMapping::empty(Address::from_raw(Bytes::bytes(512)), MemoryPermissions::readonly().with_write())).is_ok());
+    assert_eq!(memory_map.get(Address::from_raw(Bytes::bytes(900))).unwrap().base, Address::from_raw(Bytes::bytes(512)));
+    assert_eq!(memory_map.get(Address::from_raw(Bytes::bytes(100))).unwrap().base, Address::from_raw(Bytes::bytes(32)));
+}
+
+#[test]
+fn memory_bytes_test() {
+    let mut memory = Memory::new(0x1000).with_num_cells(Cells::cells(4));
+    let address = |offset: usize| Address::from_raw(Bytes::bytes(0x1000 + offset));
+    let xt = ExecutionToken::Definition(address(0x40));
+
+    // cells and bytes share the same representation
+    assert!(memory.write(address(0), 0x0102_i64).is_ok());
+    assert_eq!(memory.read_byte(address(1)), Ok(0x01));
+    assert!(memory.write_byte(address(2), 0x03).is_ok());
+    assert_eq!(memory.read::<generic_numbers::Number>(address(0)), Ok(0x030102));
+
+    // execution tokens survive a cell aligned copy, but not a misaligned one
+    assert!(memory.write(address(8), xt).is_ok());
+    assert_eq!(memory.read_bytes(address(8), Bytes::bytes(2)), Ok(vec![0x40, 0x10]));
+    assert!(memory.copy_bytes(address(8), address(16), Cells::one().to_bytes()).is_ok());
+    assert!(matches!(memory.read_value(address(16)), Ok(value::Value::ExecutionToken(copied)) if copied == xt));
+    assert!(memory.copy_bytes(address(8), address(20), Cells::one().to_bytes()).is_ok());
+    assert!(matches!(memory.read_value(address(16)), Ok(value::Value::Number(_))));
+
+    assert!(memory.fill_bytes(address(24), Bytes::bytes(9), 0).is_err());
+}
@@ -3,4 +3,6 @@ pub mod value;
 pub mod stack;
 pub mod memory;
 pub mod heap;
+pub mod file_memory;
+pub mod io_ports;
 pub mod units;
\ No newline at end of file
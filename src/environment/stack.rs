@@ -10,6 +10,8 @@ pub struct Stack {
     base: Address,
     stack: Vec<value::Value>,
     frame_offset: usize,
+    // pushes never fail, so going past this is caught afterwards by calling check_depth
+    max_depth: Cells,
 }
 
 impl Stack {
@@ -18,6 +20,28 @@ impl Stack {
             base: Address::from_raw(Bytes::bytes(base)), 
             stack: Vec::new(),
             frame_offset: 0,
+            max_depth: Cells::cells(usize::MAX),
+        }
+    }
+
+    pub fn with_max_depth(mut self, max_depth: Cells) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    pub fn max_depth(&self) -> Cells {
+        self.max_depth
+    }
+
+    /**
+     * If the stack has grown past its maximum depth, drops whatever went past it and returns the given error.
+     */
+    pub fn check_depth(&mut self, overflow: Error) -> ForthResult {
+        if self.stack.len() > self.max_depth.get_cells() {
+            self.stack.truncate(self.max_depth.get_cells());
+            Err(overflow)
+        } else {
+            Ok(())
         }
     }
 
@@ -48,6 +72,15 @@ impl Stack {
         Cells::cells(self.stack.len())
     }
 
+    pub fn clear(&mut self) {
+        self.stack.clear();
+        self.frame_offset = 0;
+    }
+
+    pub fn frame_offset(&self) -> Cells {
+        Cells::cells(self.frame_offset)
+    }
+
     pub fn push_frame(&mut self) {
         self.push(self.frame_offset as generic_numbers::UnsignedNumber);
         self.frame_offset = self.stack.len();
@@ -1,3 +1,22 @@
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CaseSensitivity {
+    // names match whatever their case, but keep the spelling they were defined with
+    Insensitive,
+    Sensitive,
+}
+
+impl CaseSensitivity {
+    /**
+     * What a name is compared by: the name itself when case sensitive, and the name uppercased when not.
+     */
+    pub fn key(self, name: &str) -> String {
+        match self {
+            Self::Sensitive => name.to_string(),
+            Self::Insensitive => name.to_uppercase()
+        }
+    }
+}
+
 pub struct ForthConfig {
     pub return_stack_addr: usize,
     pub stack_addr: usize,
@@ -6,9 +25,25 @@ pub struct ForthConfig {
     pub heap_addr: usize,
     pub internal_state_memory_addr: usize,
     pub anonymous_mappings_addr: usize,
+    pub input_buffer_addr: usize,
+
+    // how word names are matched
+    pub case_sensitivity: CaseSensitivity,
+
+    // the maximum number of cells on the data and return stacks, past which there is an unmapped guard page
+    pub stack_depth: usize,
+    pub return_stack_depth: usize,
+    // whether the heap keeps track of allocations, to report leaks and catch double frees and uses after free
+    pub heap_diagnostics: bool,
 
-    // the number of bytes a definition can have and be copied by the compile, word
+    // the number of bytes a definition can have and still be inlined into the definitions that use it
     pub definition_copy_threshold: usize,
+    // whether small leaf definitions are inlined automatically, rather than only when marked with INLINE
+    pub automatic_inlining: bool,
+    // whether finished definitions are run through the peephole optimizer
+    pub peephole_optimizations: bool,
+    // whether a call at the end of a definition jumps to the callee instead, so deep recursion doesn't grow the return stack
+    pub tail_call_elimination: bool,
 }
 
 impl Default for ForthConfig {
@@ -21,7 +56,15 @@ impl Default for ForthConfig {
             heap_addr: 0x44ea5c69c000,
             internal_state_memory_addr: 0x5deadbeef000,
             anonymous_mappings_addr: 0x55bedead1000,
-            definition_copy_threshold: 0x20
+            input_buffer_addr: 0x61bbe0ff0000,
+            case_sensitivity: CaseSensitivity::Insensitive,
+            stack_depth: 0x10000,
+            return_stack_depth: 0x10000,
+            heap_diagnostics: false,
+            definition_copy_threshold: 0x40,
+            automatic_inlining: true,
+            peephole_optimizations: true,
+            tail_call_elimination: true,
         }
     }
 }
\ No newline at end of file
@@ -4,7 +4,7 @@ use std::hash::{Hash, Hasher};
 use crate::environment::{memory, generic_numbers, stack, value};
 use crate::operations;
 use crate::io::tokens;
-use super::{ForthResult, Error};
+use super::{ForthResult, Error, config::CaseSensitivity};
 
 
 #[derive(Clone, Copy)]
@@ -117,13 +117,20 @@ pub enum NameTag {
     TempDefinition(usize),
 }
 
+/**
+ * The dictionary.  Names are looked up by their key, which is the name itself when case sensitive, and the name uppercased
+ * when not.  Either way, each definition keeps the name as it was spelled when it was defined.
+ */
 pub struct DefinitionTable {
     nametag_map: HashMap<String, usize>,
+    names: Vec<String>,
     definitions: Vec<Definition>,
     most_recent: usize,
 
     temp_nametag_map: HashMap<String, usize>,
     temp_definitions: Vec<Definition>,
+
+    case_sensitivity: CaseSensitivity,
 }
 
 impl DefinitionTable {
@@ -132,27 +139,60 @@ impl DefinitionTable {
     }
 
     pub fn from_definitions(definitions: Vec<Definition>, nametag_map: HashMap<String, usize>) -> Self {
+        let mut names = vec![String::new(); definitions.len()];
+        for (name, index) in nametag_map.iter() {
+            names[*index] = name.clone();
+        }
+
         Self {
             nametag_map,
+            names,
             definitions,
             most_recent: 0,
 
             temp_nametag_map: HashMap::new(),
             temp_definitions: Vec::new(),
+
+            case_sensitivity: CaseSensitivity::Insensitive,
         }
     }
+
+    pub fn with_case_sensitivity(mut self, case_sensitivity: CaseSensitivity) -> Self {
+        self.case_sensitivity = case_sensitivity;
+        self
+    }
+
+    /**
+     * The key that a name is looked up by.
+     */
+    pub fn key(&self, name: &str) -> String {
+        self.case_sensitivity.key(name)
+    }
+
+    pub fn case_sensitivity(&self) -> CaseSensitivity {
+        self.case_sensitivity
+    }
+
+    /**
+     * Every name that can be looked up, as it was spelled, in order.
+     */
+    pub fn names(&self) -> Vec<String> {
+        let mut names = self.nametag_map.values().map(|index| self.names[*index].clone()).collect::<Vec<_>>();
+        names.sort();
+        names
+    }
     
     pub fn get_from_token(&self, token: tokens::Token) -> Result<Definition, Error> {
-        match token {
-            tokens::Token::Integer(i) => Ok(Definition::new(ExecutionToken::Number(i), false)),
-            tokens::Token::Word(word) => self.get_from_str(&word),
-        }
+        // only words that aren't defined are read as numbers
+        self.get_from_str(&token.word)
+            .or_else(|error| token.number().map(|i| Definition::new(ExecutionToken::Number(i), false)).ok_or(error))
     }
 
     pub fn get_from_str(&self, name: &str) -> Result<Definition, Error> {
-        self.nametag_map.get(name).map(|nametag| self.definitions[*nametag])
-            .or_else(|| self.temp_nametag_map.get(name).map(|nametag| self.temp_definitions[*nametag]))
-            .ok_or(Error::UnknownWord(name.to_string()))
+        let key = self.key(name);
+        self.nametag_map.get(&key).map(|nametag| self.definitions[*nametag])
+            .or_else(|| self.temp_nametag_map.get(&key).map(|nametag| self.temp_definitions[*nametag]))
+            .ok_or(Error::UnknownWord(key))
     }
 
     pub fn get_by_index(&self, index: usize) -> Result<Definition, Error> {
@@ -190,9 +230,10 @@ impl DefinitionTable {
     }
 
     pub fn get_nametag(&self, name: &str) -> Result<NameTag, Error> {
-        self.nametag_map.get(name).map(|nametag| NameTag::Definition(*nametag))
-            .or_else(|| self.temp_nametag_map.get(name).map(|nametag| NameTag::TempDefinition(*nametag)))
-            .ok_or(Error::UnknownWord(name.to_string()))
+        let key = self.key(name);
+        self.nametag_map.get(&key).map(|nametag| NameTag::Definition(*nametag))
+            .or_else(|| self.temp_nametag_map.get(&key).map(|nametag| NameTag::TempDefinition(*nametag)))
+            .ok_or(Error::UnknownWord(key))
     } 
 
     // fn get_nametag(&self, word: &str) -> Result<NameTag, Error> {
@@ -209,17 +250,48 @@ impl DefinitionTable {
 
     pub fn add(&mut self, word: String, definition: Definition) {
         let index = self.definitions.len();
-        self.nametag_map.insert(word, index);
+        self.nametag_map.insert(self.key(&word), index);
+        self.names.push(word);
         self.definitions.push(definition);
         self.most_recent = index;
     }
 
+    pub fn len(&self) -> usize {
+        self.definitions.len()
+    }
+
+    /**
+     * Forgets every definition after the first len, so that any names they took over go back to what they were before.
+     */
+    pub fn truncate(&mut self, len: usize) {
+        while self.definitions.len() > len {
+            self.definitions.pop();
+            let key = self.names.pop().map(|name| self.key(&name)).unwrap_or_default();
+            match self.names.iter().rposition(|name| self.key(name) == key) {
+                Some(index) => { self.nametag_map.insert(key, index); },
+                None => { self.nametag_map.remove(&key); }
+            }
+        }
+        self.most_recent = self.most_recent.min(len.saturating_sub(1));
+    }
+
     pub fn add_temp(&mut self, word: String, definition: Definition) {
         let index = self.temp_definitions.len();
-        self.temp_nametag_map.insert(word, index);
+        self.temp_nametag_map.insert(self.key(&word), index);
         self.temp_definitions.push(definition);
     }
 
+    /**
+     * The name a definition was given, as it was spelled.
+     */
+    pub fn get_name(&self, index: usize) -> Option<&String> {
+        self.names.get(index)
+    }
+
+    pub fn has_temp(&self) -> bool {
+        !self.temp_definitions.is_empty()
+    }
+
     pub fn clear_temp(&mut self) {
         self.temp_nametag_map = HashMap::new();
         self.temp_definitions = Vec::new();
@@ -236,16 +308,32 @@ impl DefinitionTable {
             }
         }
 
-        for (word, xt) in self.nametag_map.iter().map(|(word, index)| (word, self.get_by_index(*index).unwrap().execution_token)) {
+        for (index, xt) in self.nametag_map.values().map(|index| (*index, self.get_by_index(*index).unwrap().execution_token)) {
             if equal(execution_token, xt) {
-                return Some(word.clone())
+                return Some(self.names[index].clone())
             }
         }
 
         None
     }
 
-    pub fn debug_only_get_nametag_map(&self) -> &HashMap<String, usize> {
-        return &self.nametag_map;
+    /**
+     * The name of the definition that the address is in, taken to be the closest one starting at or before it.
+     */
+    pub fn debug_only_get_containing_name(&self, address: memory::Address) -> Option<String> {
+        self.nametag_map.values()
+            .filter_map(|index| match self.definitions[*index].execution_token {
+                ExecutionToken::Definition(start) if !address.less_than(start) => Some((start.as_raw(), &self.names[*index])),
+                _ => None
+            })
+            .max_by_key(|(start, _)| *start)
+            .map(|(_, word)| word.clone())
+    }
+
+    /**
+     * The names that can be looked up, as they were spelled, along with the index of their definitions.
+     */
+    pub fn debug_only_get_names(&self) -> impl Iterator<Item = (&String, usize)> {
+        self.nametag_map.values().map(move |index| (&self.names[*index], *index))
     }
 }
\ No newline at end of file
@@ -2,18 +2,31 @@ pub mod definition;
 pub mod kernels;
 pub mod config;
 
+use std::collections::HashMap;
+
 use crate::operations;
-use crate::environment::{memory::{self, MemorySegment, Address}, stack, heap, value::{self, ValueVariant}, units::{Bytes, Cells, Pages}};
+use crate::environment::{memory::{self, MemorySegment, Address}, stack, heap, file_memory, value::{self, ValueVariant}, generic_numbers::{self, UnsignedByte}, units::{Bytes, Cells, Pages}};
 use crate::io::{tokens, output_stream};
 use crate::compiled_instructions;
+use crate::optimizer;
 
 
 pub type ForthResult = Result<(), Error>;
 
+// interpreted string literals are copied into buffers at the start of the pad, which are reused in turn
+const TRANSIENT_BUFFER_SIZE: usize = 0x400;
+const TRANSIENT_BUFFERS: usize = 2;
+// PAD follows the transient buffers
+const PAD_SIZE: usize = 0x400;
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum Error {
     DivisionByZero,
     StackUnderflow,
+    StackOverflow,
+    ReturnStackOverflow,
+    DoubleFree,
+    UseAfterFree,
     UnknownWord(String),
     InvalidWord,
     InvalidAddress,
@@ -25,11 +38,16 @@ pub enum Error {
     Exception(u64),
     InvalidSize,
     InsufficientMemory,
+    FileError(String),
+    // raised by ABORT", with its message
+    Abort(String),
     
     // this isn't a bad error, just a result that the input stream has finished cleanly
     TokenStreamEmpty,
     // this isn't a bad error, just a result that some command has asked to halt execution
-    Halt
+    Halt,
+    // nor is this, BYE or (BYE) has asked to leave the system altogether, with the exit code for the host
+    Bye(generic_numbers::Number)
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -70,15 +88,15 @@ impl Forth<'_, '_, '_, kernels::DefaultKernel> {
 
 impl<'a, 'i, 'o, KERNEL: kernels::Kernel> Forth<'a, 'i, 'o, KERNEL> {
     pub fn new(config: config::ForthConfig) -> Self {
-        let mut state = ForthState::new(config);
-        let kernel = KERNEL::new(&mut state);
-        let mut forth = Self { state, kernel };
-
+        // the preset definitions are evaluated before the kernel is attached, so that kernels only observe the user's program
+        let mut bootstrap = Forth { state: ForthState::new(config), kernel: kernels::DefaultKernel() };
         for definition in operations::UNCOMPILED_OPERATIONS.iter() {
-            forth.evaluate_string(definition).unwrap_or_else(|error| panic!("Failed to parse preset definition: {:?} {:?}", definition, error));
+            bootstrap.evaluate_string(definition).unwrap_or_else(|error| panic!("Failed to parse preset definition: {:?} {:?}", definition, error));
         }
 
-        forth
+        let mut state = bootstrap.state;
+        let kernel = KERNEL::new(&mut state);
+        Self { state, kernel }
     }
 
     pub fn set_output_stream<O: output_stream::OutputStream + 'o> (&mut self, output: O) {
@@ -105,7 +123,8 @@ impl<'a, 'i, 'o, KERNEL: kernels::Kernel> Forth<'a, 'i, 'o, KERNEL> {
     }
 
     pub fn set_input_stream<I: Iterator<Item = char> + 'i>(&mut self, stream: I) {
-        self.state.input_stream = tokens::TokenStream::new(stream);
+        // the number base carries over from one input to the next
+        self.state.input_stream.set_stream(stream);
     }
 
     pub fn evaluate_string(&mut self, input: &'i str) -> ForthResult {
@@ -145,10 +164,22 @@ pub struct ForthState<'a, 'i, 'o> {
     memory_map: memory::MemoryMap,
     // different fields of the state can be accessed by the running program as memory
     internal_state_memory: InternalStateMemory,
-    // a vector of unnamed anonymous pages
-    anonymous_pages: Vec<memory::Memory>,
-    // the address of the base of the next anonymous page
+    // a vector of unnamed anonymous pages, which are None once all of their pages have been unmapped
+    anonymous_pages: Vec<Option<memory::Memory>>,
+    // files mapped into memory, which are also None once they have been unmapped
+    file_mappings: Vec<Option<file_memory::FileMemory>>,
+    // segments registered by the host, which are None once they have been taken back
+    host_segments: Vec<Option<Box<dyn MemorySegment>>>,
+    // the address of the base of the next anonymous page or mapped file
     next_anonymous_mapping: Address,
+    // the transient buffer the next interpreted string goes in
+    next_transient_buffer: usize,
+    // the start of a utf-8 character that has only been partly written to the output
+    pub partial_character: Vec<UnsignedByte>,
+    // the text that SUBSTITUTE replaces each name with, as set by REPLACES
+    pub substitutions: HashMap<String, Vec<UnsignedByte>>,
+    // the answers that ENVIRONMENT? gives, as the cells that it pushes
+    environment: HashMap<String, Vec<value::Value>>,
     // named memory segments
     pub return_stack: stack::Stack,
     pub stack: stack::Stack,
@@ -157,12 +188,15 @@ pub struct ForthState<'a, 'i, 'o> {
     pub heap: heap::Heap,
 
     execution_mode: ExecutionMode,
+    // the top of data space and the number of definitions from before the definition being compiled, to go back to if it fails
+    unfinished_definition: Option<(Address, usize)>,
     // pointer to the next instruction to execute
     instruction_pointer: Option<Address>,
     // contains the current instruction, if any, being executed
     current_instruction: Option<definition::ExecutionToken>,
     pub definitions: definition::DefinitionTable,
     pub compiled_instructions: compiled_instructions::CompiledInstructions<'a>,
+    pub optimizer: optimizer::Optimizer,
 
     config: config::ForthConfig
 }
@@ -170,13 +204,14 @@ pub struct ForthState<'a, 'i, 'o> {
 impl<'a, 'i, 'o> ForthState<'a, 'i, 'o> {
     // initialization
     pub fn new(config: config::ForthConfig) -> Self {
-        let return_stack = stack::Stack::new(config.return_stack_addr);
-        let stack = stack::Stack::new(config.stack_addr);
+        let return_stack = stack::Stack::new(config.return_stack_addr).with_max_depth(Cells::cells(config.return_stack_depth));
+        let stack = stack::Stack::new(config.stack_addr).with_max_depth(Cells::cells(config.stack_depth));
         let data_space = memory::Memory::new(config.data_space_addr);
-        let pad = memory::Memory::new(config.pad_addr);
-        let heap = heap::Heap::new(config.heap_addr);
+        let pad = memory::Memory::new(config.pad_addr).with_num_cells(Bytes::bytes(TRANSIENT_BUFFER_SIZE * TRANSIENT_BUFFERS + PAD_SIZE).to_cells());
+        let heap = heap::Heap::new(config.heap_addr).with_diagnostics(config.heap_diagnostics);
 
         let internal_state_memory = InternalStateMemory::new(config.internal_state_memory_addr);
+        let input_stream = tokens::TokenStream::empty().with_base(Address::from_raw(Bytes::bytes(config.input_buffer_addr)));
 
         let memory_map = memory::MemoryMap::new(vec![
             memory::MemoryMapping::special(data_space.get_base(), memory::MemoryPermissions::all(), |state| &state.data_space, |state| &mut state.data_space).with_name("data_space"),
@@ -184,26 +219,71 @@ impl<'a, 'i, 'o> ForthState<'a, 'i, 'o> {
             memory::MemoryMapping::special(return_stack.get_base(), memory::MemoryPermissions::readwrite(), |state| &state.return_stack, |state| &mut state.return_stack).with_name("return_stack"),
             memory::MemoryMapping::special(pad.get_base(), memory::MemoryPermissions::readwrite(), |state| &state.pad, |state| &mut state.pad).with_name("pad"),
             memory::MemoryMapping::special(heap.get_base(), memory::MemoryPermissions::readwrite(), |state| &state.heap, |state| &mut state.heap).with_name("heap"),
-            memory::MemoryMapping::special(internal_state_memory.get_base(), memory::MemoryPermissions::readonly(), |state| state, |state| state).with_name("[internal mappings]"),
+            // registers that can't be written refuse it themselves
+            memory::MemoryMapping::special(internal_state_memory.get_base(), memory::MemoryPermissions::readwrite(), |state| state, |state| state).with_name("[internal mappings]"),
+            memory::MemoryMapping::special(input_stream.get_base(), memory::MemoryPermissions::readonly(), |state| &state.input_stream, |state| &mut state.input_stream).with_name("[input buffer]"),
+            // nothing is mapped just past the deepest the stacks can go, so addresses past them fault even if another mapping is close by
+            memory::MemoryMapping::empty(Self::stack_guard(&stack), memory::MemoryPermissions::none()).with_name("[stack guard]"),
+            memory::MemoryMapping::empty(Self::stack_guard(&return_stack), memory::MemoryPermissions::none()).with_name("[return stack guard]"),
         ]);
 
         Self {
             compiled_instructions: compiled_instructions::CompiledInstructions::new(),
-            definitions: definition::DefinitionTable::new(),
+            optimizer: optimizer::Optimizer::new(),
+            definitions: definition::DefinitionTable::new().with_case_sensitivity(config.case_sensitivity),
 
             data_space, stack, return_stack, pad, heap, memory_map, internal_state_memory, 
             anonymous_pages: Vec::new(),
+            file_mappings: Vec::new(),
+            host_segments: Vec::new(),
             next_anonymous_mapping: Address::from_raw(Bytes::bytes(config.anonymous_mappings_addr)),
+            next_transient_buffer: 0,
+            partial_character: Vec::new(),
+            substitutions: HashMap::new(),
+            environment: HashMap::new(),
 
             execution_mode: ExecutionMode::Interpret,
+            unfinished_definition: None,
             instruction_pointer: None,
             current_instruction: None,
 
             output_stream: Box::new(output_stream::DropOutputStream::new()),
-            input_stream: tokens::TokenStream::empty(),
+            input_stream,
 
             config
-        }.with_operations(operations::get_operations())
+        }.with_operations(operations::get_operations()).with_standard_environment()
+    }
+
+    // the standard ENVIRONMENT? queries, which follow from the config and the sizes of the number types
+    fn with_standard_environment(mut self) -> Self {
+        use generic_numbers::{Number, UnsignedNumber, DoubleNumber, UnsignedDoubleNumber};
+
+        self.set_environment("/COUNTED-STRING", UnsignedByte::MAX as Number);
+        self.set_environment("ADDRESS-UNIT-BITS", 8 as Number);
+        self.set_environment("MAX-CHAR", UnsignedByte::MAX as Number);
+        self.set_environment("MAX-N", Number::MAX);
+        self.set_environment("MAX-U", UnsignedNumber::MAX);
+        self.set_environment("MAX-D", DoubleNumber::MAX);
+        self.set_environment("MAX-UD", UnsignedDoubleNumber::MAX);
+        self.set_environment("STACK-CELLS", self.config.stack_depth as Number);
+        self.set_environment("RETURN-STACK-CELLS", self.config.return_stack_depth as Number);
+        self.set_environment("MAX-XCHAR", char::MAX as Number);
+        self.set_environment("XCHAR-MAXMEM", 4 as Number);
+        self.set_environment("/PAD", PAD_SIZE as Number);
+        // there is no pictured numeric output or floating point, so /HOLD goes unanswered, and FLOATING says there isn't any
+        self.set_environment("FLOATING", false as Number);
+
+        // word sets answer whether all of the word set is there
+        for (word_set, complete) in [("CORE", false), ("CORE-EXT", false), ("MEMORY-ALLOC", true), ("MEMORY-ALLOC-EXT", true),
+                ("STRING", true), ("STRING-EXT", true)].iter() {
+            self.set_environment(word_set, *complete as Number);
+        }
+
+        self
+    }
+
+    fn stack_guard(stack: &stack::Stack) -> Address {
+        stack.get_base().plus(stack.max_depth().to_bytes().to_pages().to_bytes())
     }
 
     pub fn add_operations(&mut self, operations: operations::OperationTable) {
@@ -251,7 +331,9 @@ impl<'a, 'i, 'o> ForthState<'a, 'i, 'o> {
         match mapping.mapping_type {
             memory::MappingType::Empty => Err(Error::InvalidAddress),
             memory::MappingType::Special { getter, .. } => Ok(getter(self)),
-            memory::MappingType::Anonymous { index: i } => Ok(&self.anonymous_pages[i])
+            memory::MappingType::Anonymous { index: i } => self.anonymous_pages[i].as_ref().map(|pages| pages as &dyn MemorySegment).ok_or(Error::InvalidAddress),
+            memory::MappingType::File { index: i } => self.file_mappings[i].as_ref().map(|file| file as &dyn MemorySegment).ok_or(Error::InvalidAddress),
+            memory::MappingType::Host { index: i } => self.host_segments[i].as_deref().ok_or(Error::InvalidAddress)
         }
     }
 
@@ -259,7 +341,12 @@ impl<'a, 'i, 'o> ForthState<'a, 'i, 'o> {
         match mapping.mapping_type {
             memory::MappingType::Empty => Err(Error::InvalidAddress),
             memory::MappingType::Special { mutable_getter, .. } => Ok(mutable_getter(self)),
-            memory::MappingType::Anonymous { index: i } => Ok(&mut self.anonymous_pages[i])
+            memory::MappingType::Anonymous { index: i } => self.anonymous_pages[i].as_mut().map(|pages| pages as &mut dyn MemorySegment).ok_or(Error::InvalidAddress),
+            memory::MappingType::File { index: i } => self.file_mappings[i].as_mut().map(|file| file as &mut dyn MemorySegment).ok_or(Error::InvalidAddress),
+            memory::MappingType::Host { index: i } => match self.host_segments[i].as_deref_mut() {
+                Some(segment) => Ok(segment),
+                None => Err(Error::InvalidAddress)
+            }
         }
     }
 
@@ -267,50 +354,143 @@ impl<'a, 'i, 'o> ForthState<'a, 'i, 'o> {
         self.get_memory_segment(self.memory_map.get(address)?)?.check_address(address)
     }
 
-    pub fn write<T: value::ValueVariant>(&mut self, address: Address, value: T) -> Result<(), Error> {
+    /**
+     * The mapping containing the address, as long as it allows the access.  Mappings only change on page boundaries, so a
+     * range that crosses one has to be allowed by every mapping it touches.
+     */
+    fn get_mapping(&self, address: Address, len: Bytes, access: memory::MemoryPermissions) -> Result<memory::MemoryMapping, Error> {
+        fn check_access(entry: &memory::MemoryMapping, access: memory::MemoryPermissions) -> ForthResult {
+            match entry.mapping_type {
+                memory::MappingType::Empty => Err(Error::InvalidAddress),
+                _ if !entry.permissions.allows(&access) => Err(Error::InsufficientPermissions),
+                _ => Ok(())
+            }
+        }
+
         let entry = self.memory_map.get(address)?;
-        if entry.permissions.write {
-            value.write_to_memory(self.get_mut_memory_segment(entry)?, address)
-        } else {
-            Err(Error::InsufficientPermissions)
+        check_access(&entry, access)?;
+
+        // a negative count turns into a length that runs off the end of the address space
+        let end = address.as_raw().checked_add(len.get_bytes()).map(|end| Address::from_raw(Bytes::bytes(end))).ok_or(Error::InvalidAddress)?;
+        if len > Bytes::zero() && address.as_raw() / memory::PAGE_SIZE != (end.as_raw() - 1) / memory::PAGE_SIZE {
+            for entry in self.memory_map.entries_between(address.plus(Bytes::one()), end) {
+                check_access(entry, access)?;
+            }
         }
+
+        Ok(entry)
+    }
+
+    pub fn write<T: value::ValueVariant>(&mut self, address: Address, value: T) -> Result<(), Error> {
+        let entry = self.get_mapping(address, Cells::cells(T::size()).to_bytes(), memory::MemoryPermissions::none().with_write())?;
+        value.write_to_memory(self.get_mut_memory_segment(entry)?, address)
     }
 
     pub fn read<T: value::ValueVariant>(&self, address: Address) -> Result<T, Error> {
-        let entry = self.memory_map.get(address)?;
-        if entry.permissions.read {
-            T::read_from_memory(self.get_memory_segment(entry)?, address)
-        } else {
-            Err(Error::InsufficientPermissions)
-        }
+        let entry = self.get_mapping(address, Cells::cells(T::size()).to_bytes(), memory::MemoryPermissions::readonly())?;
+        T::read_from_memory(self.get_memory_segment(entry)?, address)
     }
 
     pub fn write_values(&mut self, address: Address, values: &[value::Value]) -> ForthResult {
-        let entry = self.memory_map.get(address)?;
-        if entry.permissions.write {
-            self.get_mut_memory_segment(entry)?.write_values(address, values)
-        } else {
-            Err(Error::InsufficientPermissions)
-        }
+        let entry = self.get_mapping(address, Cells::cells(values.len()).to_bytes(), memory::MemoryPermissions::none().with_write())?;
+        self.get_mut_memory_segment(entry)?.write_values(address, values)
     }
 
     pub fn read_values(&self, address: Address, len: Cells) -> Result<Vec<value::Value>, Error> {
-        let entry = self.memory_map.get(address)?;
-        if entry.permissions.read {
-            self.get_memory_segment(entry)?.read_values(address, len)
+        let entry = self.get_mapping(address, len.to_bytes(), memory::MemoryPermissions::readonly())?;
+        self.get_memory_segment(entry)?.read_values(address, len)
+    }
+
+    pub fn write_bytes(&mut self, address: Address, bytes: &[UnsignedByte]) -> ForthResult {
+        let entry = self.get_mapping(address, Bytes::bytes(bytes.len()), memory::MemoryPermissions::none().with_write())?;
+        self.get_mut_memory_segment(entry)?.write_bytes(address, bytes)
+    }
+
+    pub fn read_bytes(&self, address: Address, len: Bytes) -> Result<Vec<UnsignedByte>, Error> {
+        let entry = self.get_mapping(address, len, memory::MemoryPermissions::readonly())?;
+        self.get_memory_segment(entry)?.read_bytes(address, len)
+    }
+
+    pub fn fill_bytes(&mut self, address: Address, len: Bytes, byte: UnsignedByte) -> ForthResult {
+        let entry = self.get_mapping(address, len, memory::MemoryPermissions::none().with_write())?;
+        self.get_mut_memory_segment(entry)?.fill_bytes(address, len, byte)
+    }
+
+    /**
+     * Gives ENVIRONMENT? an answer for a query, replacing any answer it already had.  The value is pushed the way it would be
+     * by the stack, so a double number answers with two cells.  Hosts can use this to describe what they add to the system.
+     */
+    pub fn set_environment<T: ValueVariant>(&mut self, name: &str, value: T) {
+        let mut cells = stack::Stack::new(0);
+        cells.push(value);
+        self.environment.insert(self.definitions.key(name), cells.to_vec());
+    }
+
+    pub fn environment(&self, name: &str) -> Option<&Vec<value::Value>> {
+        self.environment.get(&self.definitions.key(name))
+    }
+
+    /**
+     * Copies a string into the next transient buffer, where it stays until that buffer comes around again.
+     */
+    pub fn transient_string(&mut self, bytes: &[UnsignedByte]) -> Result<Address, Error> {
+        if bytes.len() > TRANSIENT_BUFFER_SIZE {
+            return Err(Error::InvalidSize)
+        }
+
+        let address = self.pad.get_base().plus(Bytes::bytes(self.next_transient_buffer * TRANSIENT_BUFFER_SIZE));
+        self.next_transient_buffer = (self.next_transient_buffer + 1) % TRANSIENT_BUFFERS;
+        self.pad.write_bytes(address, bytes)?;
+        Ok(address)
+    }
+
+    /**
+     * The space that PAD gives to the program, which nothing else writes to.
+     */
+    pub fn pad_address(&self) -> Address {
+        self.pad.get_base().plus(Bytes::bytes(TRANSIENT_BUFFER_SIZE * TRANSIENT_BUFFERS))
+    }
+
+    /**
+     * Copies bytes as if through a temporary buffer, so the ranges can overlap.  Both ranges have to be within a single mapping,
+     * and execution tokens in whole cells come along, as long as the source and destination are aligned the same way.
+     */
+    pub fn copy_bytes(&mut self, source: Address, destination: Address, len: Bytes) -> ForthResult {
+        if len == Bytes::zero() {
+            return Ok(())
+        }
+
+        let from = self.get_mapping(source, len, memory::MemoryPermissions::readonly())?;
+        let to = self.get_mapping(destination, len, memory::MemoryPermissions::none().with_write())?;
+        if from.base == to.base {
+            self.get_mut_memory_segment(to)?.copy_bytes(source, destination, len)
         } else {
-            Err(Error::InsufficientPermissions)
+            let segment = self.get_memory_segment(from)?;
+            let bytes = segment.read_bytes(source, len)?;
+
+            // copy the whole cells again as values, which keeps execution tokens
+            let skipped = source.nearest_cell().offset_from(source);
+            let cells = if source.get_cell_byte() == destination.get_cell_byte() && skipped < len {
+                (len - skipped).containing_cells()
+            } else {
+                Cells::zero()
+            };
+            let values = if cells > Cells::zero() { segment.read_values(source.plus(skipped), cells)? } else { Vec::new() };
+
+            let segment = self.get_mut_memory_segment(to)?;
+            segment.write_bytes(destination, &bytes)?;
+            if values.is_empty() {
+                Ok(())
+            } else {
+                segment.write_values(destination.plus(skipped), &values)
+            }
         }
     }
 
     fn read_instruction_pointer(&self) -> Result<definition::ExecutionToken, Error> {
         let address = self.instruction_pointer.ok_or(Error::InvalidAddress)?;
-        let entry = self.memory_map.get(address)?;
-        if entry.permissions.execute {
-            definition::ExecutionToken::read_from_memory(self.get_memory_segment(entry)?, address)
-        } else {
-            Err(Error::InsufficientPermissions)
-        }
+        let entry = self.get_mapping(address, Cells::one().to_bytes(), memory::MemoryPermissions::none().with_execute())?;
+        definition::ExecutionToken::read_from_memory(self.get_memory_segment(entry)?, address)
     }
 
     pub fn create_anonymous_mapping(&mut self, num_pages: Pages) -> Result<Address, Error> {
@@ -323,11 +503,141 @@ impl<'a, 'i, 'o> ForthState<'a, 'i, 'o> {
     pub fn create_anonymous_mapping_at(&mut self, address: Address, num_pages: Pages) -> Result<Address, Error> {
         let index = self.anonymous_pages.len();
 
-        self.anonymous_pages
-            .push(memory::Memory::new(address.as_raw())
-            .with_num_cells(num_pages.to_cells()));
-        self.memory_map.add(memory::MemoryMapping::anonymous(address, memory::MemoryPermissions::readwrite(), index))
-            .map(|_| address)
+        self.memory_map.add(memory::MemoryMapping::anonymous(address, memory::MemoryPermissions::readwrite(), index))?;
+        self.anonymous_pages.push(Some(memory::Memory::new(address.as_raw()).with_num_cells(num_pages.to_cells())));
+        Ok(address)
+    }
+
+    /**
+     * Maps a file on the host into memory, with the given permissions.  The file is read in lazily, a page at a time, and a
+     * writable mapping is copy-on-write: changes to it are never written back to the file.
+     */
+    pub fn map_file<P: AsRef<std::path::Path>>(&mut self, path: P, permissions: memory::MemoryPermissions) -> Result<Address, Error> {
+        let base = self.next_anonymous_mapping;
+        let file = file_memory::FileMemory::open(base, path)?;
+        if file.is_empty() {
+            return Err(Error::InvalidSize)
+        }
+
+        let index = self.file_mappings.len();
+        self.memory_map.add(memory::MemoryMapping::file(base, permissions, index))?;
+        self.next_anonymous_mapping = file.page_end();
+        self.file_mappings.push(Some(file));
+        Ok(base)
+    }
+
+    /**
+     * Makes a segment of memory provided by the host available to the program, at the segment's own base address, so that
+     * all of the memory words can use it.  It can't overlap anything that's already mapped.
+     */
+    pub fn register_segment(&mut self, segment: Box<dyn MemorySegment>, permissions: memory::MemoryPermissions, name: &'static str) -> Result<Address, Error> {
+        let (base, end) = (segment.get_base(), segment.get_end());
+        let overlaps = self.query_mapping(base).is_some() || self.memory_map.entries_between(base, end).iter()
+            .any(|mapping| !matches!(mapping.mapping_type, memory::MappingType::Empty));
+        if overlaps {
+            return Err(Error::InvalidAddress)
+        }
+
+        let index = self.host_segments.len();
+        self.memory_map.add(memory::MemoryMapping::host(base, permissions, index).with_name(name))?;
+        self.host_segments.push(Some(segment));
+        Ok(base)
+    }
+
+    /**
+     * Takes back a segment registered with register_segment, leaving nothing mapped in its place.
+     */
+    pub fn unregister_segment(&mut self, base: Address) -> Result<Box<dyn MemorySegment>, Error> {
+        let mapping = self.memory_map.get(base)?;
+        match mapping.mapping_type {
+            memory::MappingType::Host { index } if mapping.base == base => {
+                let segment = self.host_segments[index].take().ok_or(Error::InvalidAddress)?;
+                self.memory_map.replace(base, base.plus(Bytes::one()), memory::MemoryMapping::empty(base, memory::MemoryPermissions::none()))?;
+                Ok(segment)
+            },
+            _ => Err(Error::InvalidAddress)
+        }
+    }
+
+    // the end of the pages behind an anonymous or file mapping, which are the mappings that can be protected and unmapped
+    fn mapped_pages_end(&self, mapping_type: memory::MappingType) -> Option<Address> {
+        match mapping_type {
+            memory::MappingType::Anonymous { index } => self.anonymous_pages[index].as_ref().map(|pages| pages.get_end()),
+            memory::MappingType::File { index } => self.file_mappings[index].as_ref().map(|file| file.page_end()),
+            _ => None
+        }
+    }
+
+    fn same_pages(a: memory::MappingType, b: memory::MappingType) -> bool {
+        match (a, b) {
+            (memory::MappingType::Anonymous { index: i }, memory::MappingType::Anonymous { index: j }) => i == j,
+            (memory::MappingType::File { index: i }, memory::MappingType::File { index: j }) => i == j,
+            _ => false
+        }
+    }
+
+    // checks that the pages starting at address are all part of the same anonymous or file mapping, returning its type and the end of the pages
+    fn mapped_pages_range(&self, address: Address, num_pages: Pages) -> Result<(memory::MappingType, Address), Error> {
+        if !address.as_raw().is_multiple_of(memory::PAGE_SIZE) || num_pages == Pages::zero() {
+            return Err(Error::InvalidAddress)
+        }
+
+        let end = address.plus(num_pages.to_bytes());
+        let mapping_type = self.memory_map.get(address)?.mapping_type;
+        let pages_end = self.mapped_pages_end(mapping_type).ok_or(Error::InvalidAddress)?;
+        let same_mapping = self.memory_map.entries_between(address, end).iter()
+            .all(|mapping| Self::same_pages(mapping.mapping_type, mapping_type));
+        if pages_end.less_than(end) || !same_mapping {
+            return Err(Error::InvalidAddress)
+        }
+
+        Ok((mapping_type, end))
+    }
+
+    // split the mappings so that the pages between address and end have entries of their own
+    fn isolate_pages(&mut self, mapping_type: memory::MappingType, address: Address, end: Address) -> ForthResult {
+        self.memory_map.split(address)?;
+        match self.mapped_pages_end(mapping_type) {
+            Some(pages_end) if end.less_than(pages_end) => self.memory_map.split(end),
+            _ => Ok(())
+        }
+    }
+
+    /**
+     * Changes the permissions of some of the pages of an anonymous or file mapping.  The pages can be part of a larger mapping, which is split up.
+     */
+    pub fn protect_mapping(&mut self, address: Address, num_pages: Pages, permissions: memory::MemoryPermissions) -> ForthResult {
+        let (mapping_type, end) = self.mapped_pages_range(address, num_pages)?;
+        self.isolate_pages(mapping_type, address, end)?;
+        self.memory_map.set_permissions(address, end, permissions);
+        Ok(())
+    }
+
+    /**
+     * Unmaps some of the pages of an anonymous or file mapping, releasing the memory (or file) behind it once none of its pages are mapped.
+     */
+    pub fn unmap_mapping(&mut self, address: Address, num_pages: Pages) -> ForthResult {
+        let (mapping_type, end) = self.mapped_pages_range(address, num_pages)?;
+        self.isolate_pages(mapping_type, address, end)?;
+        self.memory_map.replace(address, end, memory::MemoryMapping::empty(address, memory::MemoryPermissions::none()))?;
+
+        let still_mapped = self.memory_map.get_entries().iter()
+            .any(|mapping| Self::same_pages(mapping.mapping_type, mapping_type));
+        match mapping_type {
+            memory::MappingType::Anonymous { index } if !still_mapped => self.anonymous_pages[index] = None,
+            memory::MappingType::File { index } if !still_mapped => self.file_mappings[index] = None,
+            _ => ()
+        }
+
+        Ok(())
+    }
+
+    /**
+     * The mapping an address is part of, if the address is actually backed by memory.
+     */
+    pub fn query_mapping(&self, address: Address) -> Option<memory::MemoryMapping> {
+        self.memory_map.get(address).ok()
+            .filter(|mapping| self.get_memory_segment(*mapping).and_then(|segment| segment.check_address(address)).is_ok())
     }
 
     // execution instructions
@@ -380,17 +690,51 @@ impl<'a, 'i, 'o> ForthState<'a, 'i, 'o> {
         Ok(())
     }
 
+    // remembers where a new definition starts, so that it can be thrown away if it is never finished
+    pub fn begin_definition(&mut self) {
+        if self.unfinished_definition.is_none() {
+            self.unfinished_definition = Some((self.data_space.top(), self.definitions.len()));
+        }
+    }
+
+    pub fn end_definition(&mut self) {
+        self.unfinished_definition = None;
+    }
+
+    /**
+     * Gets back to a clean state after an error, as QUIT does: both stacks are emptied, whatever was executing is abandoned,
+     * and the interpreter goes back to interpreting.  A definition that was still being compiled is forgotten, along with the
+     * data space it took up, but finished definitions and the rest of memory are left as they are.
+     */
+    pub fn reset(&mut self) {
+        if let Some((top, definitions)) = self.unfinished_definition.take() {
+            self.data_space.truncate(top);
+            self.definitions.truncate(definitions);
+        }
+        self.stack.clear();
+        self.return_stack.clear();
+        self.instruction_pointer = None;
+        self.current_instruction = None;
+        self.execution_mode = ExecutionMode::Interpret;
+        self.definitions.clear_temp();
+        self.partial_character.clear();
+    }
+
     fn fetch_current_instruction(&mut self) -> ForthResult {
         self.read_instruction_pointer().map(|current_instruction| self.current_instruction = Some(current_instruction))
-            .or_else(|_| self.input_stream.next().ok().ok_or(Error::TokenStreamEmpty)
-            .and_then(|token| self.definitions.get_from_token(token))
-            .map(|definition| if self.execution_mode == ExecutionMode::Compile && !definition.immediate {
-                self.data_space.push(definition.execution_token.value());
-                self.current_instruction = None;
-            } else {
-                self.current_instruction = Some(definition.execution_token);
+            .or_else(|error| match error {
+                // code that isn't executable is an error, rather than a reason to go back to the input
+                Error::InsufficientPermissions => Err(error),
+                _ => self.input_stream.next().ok().ok_or(Error::TokenStreamEmpty)
+                    .and_then(|token| self.definitions.get_from_token(token))
+                    .and_then(|definition| if self.execution_mode == ExecutionMode::Compile && !definition.immediate {
+                        self.current_instruction = None;
+                        optimizer::compile(self, definition.execution_token)
+                    } else {
+                        self.current_instruction = Some(definition.execution_token);
+                        Ok(())
+                    })
             })
-        )
     }
 
     fn execute_current_instruction(&mut self) -> ForthResult {
@@ -400,26 +744,43 @@ impl<'a, 'i, 'o> ForthState<'a, 'i, 'o> {
             Some(xt) => self.execute(xt),
             None => Ok(())
         }
+        .and_then(|_| self.stack.check_depth(Error::StackOverflow))
+        .and_then(|_| self.return_stack.check_depth(Error::ReturnStackOverflow))
     }
 }
 
+type RegisterRead = fn(&ForthState) -> value::Value;
+type RegisterWrite = fn(&mut ForthState, value::Value) -> ForthResult;
+
 #[derive(Clone, Copy)]
 pub struct StateRegister {
     pub address: Address,
-    read: fn(&ForthState) -> value::Value,
-    write: fn(&mut ForthState, value::Value),
+    pub name: &'static str,
+    read: RegisterRead,
+    write: RegisterWrite,
 }
 
 impl StateRegister {
-    fn new(address: Address, read: fn(&ForthState) -> value::Value, write: fn(&mut ForthState, value::Value)) -> Self {
-        Self { address, read, write }
+    fn new(address: Address, name: &'static str, read: RegisterRead, write: RegisterWrite) -> Self {
+        Self { address, name, read, write }
     }
+
+    pub fn read(&self, state: &ForthState) -> value::Value {
+        (self.read)(state)
+    }
+}
+
+// the write function of registers that can only be read
+fn read_only_register(_: &mut ForthState, _: value::Value) -> ForthResult {
+    Err(Error::InsufficientPermissions)
 }
 
 pub struct InternalStateMemory {
     pub base: Address,
     // each state register can be accessed as both a member of the internal state memory, 
     pub execution_mode: StateRegister,
+    pub number_base: StateRegister,
+    pub input_position: StateRegister,
     members: Vec<StateRegister>
 }
 
@@ -436,32 +797,59 @@ impl InternalStateMemory {
                 Self { base: Address::from_raw(Bytes::bytes(base)), members: Vec::new() }
             }
 
-            fn add(&mut self, read: fn(&ForthState) -> value::Value, write: fn(&mut ForthState, value::Value)) -> StateRegister {
-                let new = StateRegister::new(self.base.plus_cell(Cells::cells(self.members.len())), read, write);
+            fn add(&mut self, name: &'static str, read: RegisterRead, write: RegisterWrite) -> StateRegister {
+                let new = StateRegister::new(self.base.plus_cell(Cells::cells(self.members.len())), name, read, write);
                 self.members.push(new);
                 new
             }
         }
         
         let mut builder = Builder::new(base);
-        let execution_mode = builder.add(
+        let execution_mode = builder.add("STATE",
             |state| value::Value::Number(match state.execution_mode {
                 ExecutionMode::Compile => 1,
                 ExecutionMode::Interpret => 0
             }),
-            |state, value| state.execution_mode = match value.to_number() {
-                0 => ExecutionMode::Interpret,
-                _ => ExecutionMode::Compile 
+            |state, value| {
+                state.execution_mode = match value.to_number() {
+                    0 => ExecutionMode::Interpret,
+                    _ => ExecutionMode::Compile 
+                };
+                Ok(())
             }
         );
+        let number_base = builder.add("BASE",
+            |state| value::Value::Number(state.input_stream.radix() as generic_numbers::Number),
+            |state, value| state.input_stream.set_radix(value.to_number() as u32)
+        );
+        let input_position = builder.add(">IN",
+            |state| value::Value::Number(state.input_stream.position() as generic_numbers::Number),
+            |state, value| {
+                state.input_stream.set_position(value.to_number() as usize);
+                Ok(())
+            }
+        );
+        builder.add("HERE", |state| value::Value::Number(state.data_space.top().to_number()), read_only_register);
+        builder.add("SOURCE-ID", |state| value::Value::Number(state.input_stream.source_id()), read_only_register);
+        builder.add("DEPTH", |state| value::Value::Number(state.stack.len().get_cells() as generic_numbers::Number), read_only_register);
+        builder.add("RDEPTH", |state| value::Value::Number(state.return_stack.len().get_cells() as generic_numbers::Number), read_only_register);
+        builder.add("FRAME", |state| value::Value::Number(state.return_stack.frame_offset().get_cells() as generic_numbers::Number), read_only_register);
 
         Self { 
             base: Address::from_raw(Bytes::bytes(base)),
-            execution_mode,
+            execution_mode, number_base, input_position,
             members: builder.members
         }
     }
 
+    pub fn registers(&self) -> &[StateRegister] {
+        &self.members
+    }
+
+    pub fn get_register(&self, name: &str) -> Option<StateRegister> {
+        self.members.iter().find(|register| register.name == name).copied()
+    }
+
     fn len(&self) -> Cells {
          Cells::cells(self.members.len())
     }
@@ -483,7 +871,7 @@ impl memory::MemorySegment for ForthState<'_, '_, '_> {
     fn write_value(&mut self, address: Address, value: value::Value) -> Result<(), Error> {
         let index = self.cell_offset(address)?.get_cells();
         let write_function = self.internal_state_memory.members[index].write;
-        Ok(write_function(self, value))
+        write_function(self, value)
     }
 
     fn read_value(&self, address: Address) -> Result<value::Value, Error> {
@@ -499,7 +887,7 @@ impl memory::MemorySegment for ForthState<'_, '_, '_> {
 
         for (i, v) in values.iter().enumerate().map(|(i, v)| (i + start, *v)) {
             let write_function = self.internal_state_memory.members[i].write;
-            write_function(self, v);
+            write_function(self, v)?;
         }
 
         Ok(())
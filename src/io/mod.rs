@@ -1,2 +1,4 @@
 pub mod output_stream;
-pub mod tokens;
\ No newline at end of file
+pub mod tokens;
+#[cfg(feature = "repl")]
+pub mod repl;
\ No newline at end of file
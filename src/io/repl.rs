@@ -0,0 +1,173 @@
+use std::path::PathBuf;
+
+use rustyline::{Editor, Helper, Context, completion::Completer, hint::Hinter, highlight::Highlighter, validate::Validator};
+use rustyline::{history::DefaultHistory, error::ReadlineError};
+
+use crate::evaluate::{Forth, Error, ExecutionMode, kernels::Kernel, config::CaseSensitivity};
+use crate::environment::generic_numbers::Number;
+
+
+const PROMPT: &str = "> ";
+// shown while a definition is being compiled, which can go on over several lines
+const CONTINUATION_PROMPT: &str = "| ";
+
+/**
+ * Completes the word under the cursor from the names in the dictionary.  The editor can't look at the dictionary while
+ * Forth is running, so it works from a copy of the names that is taken before each line is read.
+ */
+pub struct WordCompleter {
+    words: Vec<String>,
+    case_sensitivity: CaseSensitivity,
+}
+
+impl WordCompleter {
+    /**
+     * Where the word being completed starts, and the names it could be.
+     */
+    pub fn candidates(&self, line: &str, pos: usize) -> (usize, Vec<String>) {
+        let start = line[..pos].char_indices().rev()
+            .find(|(_, c)| c.is_whitespace())
+            .map_or(0, |(i, c)| i + c.len_utf8());
+        let prefix = self.case_sensitivity.key(&line[start..pos]);
+
+        let words = self.words.iter().filter(|word| self.case_sensitivity.key(word).starts_with(&prefix)).cloned().collect();
+        (start, words)
+    }
+}
+
+impl Completer for WordCompleter {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _context: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(self.candidates(line, pos))
+    }
+}
+
+impl Hinter for WordCompleter {
+    type Hint = String;
+}
+
+impl Highlighter for WordCompleter {}
+impl Validator for WordCompleter {}
+impl Helper for WordCompleter {}
+
+/**
+ * An interactive session: lines are read with editing and completion, and evaluated one at a time.  After each line comes
+ * " ok", or " compiled" while in the middle of a definition, which carries on over the following lines.
+ */
+pub struct Repl {
+    editor: Editor<WordCompleter, DefaultHistory>,
+    history: Option<PathBuf>,
+}
+
+impl Repl {
+    pub fn new() -> rustyline::Result<Self> {
+        let mut editor = Editor::new()?;
+        editor.set_helper(Some(WordCompleter { words: Vec::new(), case_sensitivity: CaseSensitivity::Insensitive }));
+        Ok(Self { editor, history: None })
+    }
+
+    /**
+     * Keeps the history in a file, so that it carries over from one session to the next.
+     */
+    pub fn with_history<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        let path = path.into();
+        // the file won't be there the first time
+        let _ = self.editor.load_history(&path);
+        self.history = Some(path);
+        self
+    }
+
+    /**
+     * Reads and evaluates lines until the input runs out, or BYE is run.  Errors are reported and the state is reset, but
+     * the session carries on.  Gives the exit code from (BYE), which is 0 otherwise.
+     */
+    pub fn run<K: Kernel>(&mut self, forth: &mut Forth<'_, '_, '_, K>) -> rustyline::Result<Number> {
+        loop {
+            if let Some(completer) = self.editor.helper_mut() {
+                completer.words = forth.state.definitions.names();
+                completer.case_sensitivity = forth.state.definitions.case_sensitivity();
+            }
+
+            let prompt = match forth.state.execution_mode() {
+                ExecutionMode::Compile => CONTINUATION_PROMPT,
+                ExecutionMode::Interpret => PROMPT
+            };
+            let line = match self.editor.readline(prompt) {
+                Ok(line) => line,
+                // ^C throws away the line, along with any definition that was being compiled
+                Err(ReadlineError::Interrupted) => {
+                    forth.state.reset();
+                    continue
+                },
+                Err(ReadlineError::Eof) => return Ok(0),
+                Err(error) => return Err(error)
+            };
+
+            if !line.trim().is_empty() {
+                self.editor.add_history_entry(line.as_str())?;
+                // not being able to save history isn't worth ending the session over
+                if let Some(path) = &self.history {
+                    let _ = self.editor.append_history(path);
+                }
+            }
+
+            match evaluate_line(forth, &line) {
+                Ok(status) => forth.state.output_stream.writeln(&status),
+                Err(code) => return Ok(code)
+            }
+        }
+    }
+}
+
+/**
+ * Evaluates a line the way the REPL does, resetting the state if it fails, and gives the status to show after it.  If the
+ * line runs BYE, this gives the exit code instead.
+ */
+pub fn evaluate_line<K: Kernel>(forth: &mut Forth<'_, '_, '_, K>, line: &str) -> Result<String, Number> {
+    match forth.evaluate_stream(line.chars().collect::<Vec<_>>().into_iter()) {
+        Ok(()) if forth.state.execution_mode() == ExecutionMode::Compile => Ok(" compiled".to_string()),
+        Ok(()) => Ok(" ok".to_string()),
+        Err(Error::Bye(code)) => Err(code),
+        Err(error) => {
+            forth.state.reset();
+            Ok(format!(" error: {:?}", error))
+        }
+    }
+}
+
+#[test]
+fn completion_test() {
+    let forth = Forth::default();
+    let completer = WordCompleter { words: forth.state.definitions.names(), case_sensitivity: CaseSensitivity::Insensitive };
+
+    // only the word under the cursor is completed, whatever its case
+    let (start, words) = completer.candidates("1 2 sw", 6);
+    assert_eq!(start, 4);
+    assert_eq!(words, vec!["SWAP".to_string()]);
+    assert!(completer.candidates("cmove", 5).1.iter().eq(["CMOVE", "CMOVE>"].iter()));
+    assert_eq!(completer.candidates("xyz ", 4).1.len(), completer.words.len());
+}
+
+#[test]
+fn failed_definition_test() {
+    use crate::io::output_stream::BufferedOutputStream;
+    let mut forth = Forth::default().with_output_stream(BufferedOutputStream::new());
+
+    // a definition that fails part way through is forgotten, so the word it was replacing is back
+    assert!(evaluate_line(&mut forth, ": dup oops ;").unwrap().starts_with(" error"));
+    assert_eq!(evaluate_line(&mut forth, "3 dup . ."), Ok(" ok".to_string()));
+    assert_eq!(forth.state.output_stream.consume(), "3 3 ");
+
+    // along with the data space it took up, so the next definition doesn't run on from it
+    assert!(evaluate_line(&mut forth, ": foo 1 bogus").unwrap().starts_with(" error"));
+    assert_eq!(evaluate_line(&mut forth, ": bar 42 . ;"), Ok(" ok".to_string()));
+    assert!(evaluate_line(&mut forth, "foo").unwrap().starts_with(" error: UnknownWord"));
+    assert_eq!(evaluate_line(&mut forth, "bar depth ."), Ok(" ok".to_string()));
+    assert_eq!(forth.state.output_stream.consume(), "42 0 ");
+
+    // definitions spread over several lines are only finished by the last one
+    assert_eq!(evaluate_line(&mut forth, ": baz 7"), Ok(" compiled".to_string()));
+    assert_eq!(evaluate_line(&mut forth, ". ; baz"), Ok(" ok".to_string()));
+    assert_eq!(forth.state.output_stream.consume(), "7 ");
+}
@@ -1,71 +1,413 @@
-use crate::evaluate::Error;
-use crate::environment::generic_numbers;
+use crate::evaluate::{ForthResult, Error};
+use crate::environment::{generic_numbers, value::Value, units::{Bytes, Cells}};
+use crate::environment::generic_numbers::{Number, UnsignedByte, AsValue};
+use crate::environment::memory::{MemorySegment, Address, CELL_SIZE};
 
 
+// the value of SOURCE-ID for each kind of input source
+const USER_INPUT_SOURCE: Number = 0;
+const STRING_SOURCE: Number = -1;
+
+/**
+ * Somewhere input is read from.  Input is parsed a line at a time out of the buffer, and >IN is the byte offset into it.
+ * Strings passed to EVALUATE are a source with a single line, and no stream to refill it from.
+ */
+struct InputSource<'a> {
+    stream: Option<Box<dyn Iterator<Item = char> + 'a>>,
+    id: Number,
+    buffer: String,
+    position: usize,
+    // the number of lines read in so far
+    line: usize,
+    // whether the buffer has been filled at all, so the first refill isn't mistaken for the end of a line
+    filled: bool,
+}
+
+impl<'a> InputSource<'a> {
+    fn stream<I: Iterator<Item = char> + 'a>(stream: I) -> Self {
+        Self { stream: Some(Box::new(stream)), id: USER_INPUT_SOURCE, buffer: String::new(), position: 0, line: 0, filled: false }
+    }
+
+    fn string(string: String) -> Self {
+        Self { stream: None, id: STRING_SOURCE, buffer: string, position: 0, line: 1, filled: true }
+    }
+
+    /**
+     * Reads the next line of the stream into the buffer.  False if there is no stream, or nothing left in it.
+     */
+    fn refill(&mut self) -> bool {
+        let stream = match self.stream.as_mut() {
+            Some(stream) => stream,
+            None => return false
+        };
+
+        let mut line = String::new();
+        let mut ended = false;
+        for c in stream.by_ref() {
+            if c == '\n' {
+                ended = true;
+                break
+            }
+            line.push(c);
+        }
+
+        if line.is_empty() && !ended {
+            return false
+        }
+
+        if line.ends_with('\r') {
+            line.pop();
+        }
+        self.buffer = line;
+        self.position = 0;
+        self.line += 1;
+        self.filled = true;
+        true
+    }
+
+    fn rest(&self) -> &str {
+        &self.buffer[self.position..]
+    }
+
+    // moves past the characters at the start of the rest of the buffer that match, returning the byte offset that it stopped at
+    fn skip_while(&mut self, mut predicate: impl FnMut(char) -> bool) -> usize {
+        let skipped = self.rest().char_indices().find(|(_, c)| !predicate(*c)).map_or(self.rest().len(), |(i, _)| i);
+        self.position += skipped;
+        self.position
+    }
+
+    /**
+     * Parses up to a delimiter, which is consumed along with the parsed text.  Gives the byte range of the text.
+     */
+    fn parse(&mut self, mut is_delimiter: impl FnMut(char) -> bool) -> (usize, usize) {
+        let start = self.position;
+        let end = self.skip_while(|c| !is_delimiter(c));
+        if let Some(delimiter) = self.rest().chars().next() {
+            self.position += delimiter.len_utf8();
+        }
+        (start, end - start)
+    }
+
+    fn parse_name(&mut self) -> (usize, usize) {
+        self.skip_while(char::is_whitespace);
+        self.parse(char::is_whitespace)
+    }
+}
+
+/**
+ * The stack of input sources being read from.  The last source is the current one, and the sources under it are where
+ * parsing picks up again once it runs out, so EVALUATE can nest a string in the middle of a line.  The buffer of the
+ * current source is mapped into memory at the base, which is where SOURCE points.
+ */
 pub struct TokenStream<'a> {
-    stream: Box<dyn Iterator<Item = char> + 'a>
+    sources: Vec<InputSource<'a>>,
+    base: Address,
+    // the line that the most recent token started on
+    token_line: usize,
+    // the base that numbers are parsed in
+    radix: u32,
 }
 
 impl<'a> TokenStream<'a> {
     pub fn new<I: Iterator<Item = char> + 'a>(stream: I) -> Self {
-        Self { stream: Box::new(stream) }
+        Self { sources: vec![InputSource::stream(stream)], base: Address::from_raw(Bytes::zero()), token_line: 1, radix: 10 }
+    }
+
+    pub fn with_radix(mut self, radix: u32) -> Self {
+        self.radix = radix;
+        self
+    }
+
+    pub fn with_base(mut self, base: Address) -> Self {
+        self.base = base;
+        self
     }
 
     pub fn empty() -> Self {
-        Self { stream: Box::new(std::iter::empty()) }
+        Self::new(std::iter::empty())
+    }
+
+    /**
+     * Starts reading from a new stream, dropping whatever was left of the old input.
+     */
+    pub fn set_stream<I: Iterator<Item = char> + 'a>(&mut self, stream: I) {
+        self.sources = vec![InputSource::stream(stream)];
+        self.token_line = 1;
+    }
+
+    /**
+     * Parses a string before the rest of the input, as EVALUATE does.
+     */
+    pub fn push_string(&mut self, string: String) {
+        self.sources.push(InputSource::string(string));
+    }
+
+    fn current(&self) -> &InputSource<'a> {
+        self.sources.last().unwrap()
+    }
+
+    fn current_mut(&mut self) -> &mut InputSource<'a> {
+        self.sources.last_mut().unwrap()
+    }
+
+    /**
+     * The line (starting from 1) of the most recently read token.  Lines are counted in the user's input, so tokens from
+     * evaluated strings are on the line that evaluated them.
+     */
+    pub fn line(&self) -> usize {
+        self.token_line
+    }
+
+    pub fn radix(&self) -> u32 {
+        self.radix
+    }
+
+    pub fn set_radix(&mut self, radix: u32) -> Result<(), Error> {
+        if !(2..=36).contains(&radix) {
+            return Err(Error::InvalidNumber)
+        }
+
+        self.radix = radix;
+        Ok(())
+    }
+
+    /**
+     * The offset into the input buffer that parsing has reached, which is what >IN holds.
+     */
+    pub fn position(&self) -> usize {
+        self.current().position
+    }
+
+    /**
+     * Moves parsing to an offset in the input buffer, in either direction.  Offsets past the end of the buffer, or in the
+     * middle of a character, go to the end of it or the start of the character.
+     */
+    pub fn set_position(&mut self, position: usize) {
+        let source = self.current_mut();
+        let mut position = position.min(source.buffer.len());
+        while !source.buffer.is_char_boundary(position) {
+            position -= 1;
+        }
+        source.position = position;
+    }
+
+    /**
+     * What SOURCE-ID gives: 0 for the user input device, and -1 for a string being evaluated.
+     */
+    pub fn source_id(&self) -> Number {
+        self.current().id
+    }
+
+    /**
+     * The address and length of the input buffer.
+     */
+    pub fn source(&self) -> (Address, Bytes) {
+        (self.base, Bytes::bytes(self.current().buffer.len()))
+    }
+
+    pub fn skip_line(&mut self) {
+        let source = self.current_mut();
+        source.position = source.buffer.len();
+    }
+
+    /**
+     * Reads the next line of the current source into the buffer, as REFILL does.  Evaluated strings can't be refilled.
+     */
+    pub fn refill(&mut self) -> bool {
+        self.current_mut().refill()
+    }
+
+    // gets more input once the buffer runs out, by refilling it or going back to the source under it
+    fn next_line(&mut self) -> bool {
+        if self.refill() {
+            true
+        } else if self.sources.len() > 1 {
+            self.sources.pop();
+            true
+        } else {
+            false
+        }
+    }
+
+    /**
+     * Parses the input buffer up to the delimiter, giving the address and length of the text in it.
+     */
+    pub fn parse(&mut self, delimiter: char) -> (Address, Bytes) {
+        let (start, len) = self.current_mut().parse(|c| c == delimiter);
+        (self.base.plus(Bytes::bytes(start)), Bytes::bytes(len))
+    }
+
+    /**
+     * Parses like PARSE, but gives the text itself, and takes a function to find the delimiter with.  The function sees
+     * every character in turn, so it can keep track of escapes.
+     */
+    pub fn parse_text(&mut self, is_delimiter: impl FnMut(char) -> bool) -> String {
+        let (start, len) = self.current_mut().parse(is_delimiter);
+        self.current().buffer[start..start + len].to_string()
+    }
+
+    /**
+     * Skips leading whitespace, then parses up to the next whitespace.  The name is empty when the buffer runs out.
+     */
+    pub fn parse_name(&mut self) -> (Address, Bytes) {
+        let (start, len) = self.current_mut().parse_name();
+        (self.base.plus(Bytes::bytes(start)), Bytes::bytes(len))
+    }
+
+    /**
+     * Where parsing is up to, for RESTORE-INPUT to go back to.
+     */
+    pub fn save(&self) -> Vec<Number> {
+        vec![self.sources.len() as Number, self.current().line as Number, self.current().position as Number]
+    }
+
+    /**
+     * Goes back to a position from SAVE-INPUT.  Only the current line is kept, so this fails if the input has moved on.
+     */
+    pub fn restore(&mut self, saved: &[Number]) -> Result<(), Error> {
+        match *saved {
+            [depth, line, position] if depth as usize == self.sources.len() && line as usize == self.current().line => {
+                self.set_position(position as usize);
+                Ok(())
+            },
+            _ => Err(Error::InvalidNumber)
+        }
     }
 
     pub fn next(&mut self) -> Result<Token, Error> {
-        let mut s = String::new();
-        if let Some(c) = self.stream.find(|c| !c.is_whitespace()) {
-            s.push(c);
-            while let Some(next_char) = self.stream.next() {
-                if next_char.is_whitespace() {
-                    break;
-                } else {
-                    s.push(next_char)
-                }
+        loop {
+            let (start, len) = self.current_mut().parse_name();
+            if len > 0 {
+                self.token_line = self.sources[0].line;
+                return Ok(Token::new(&self.current().buffer[start..start + len], self.radix))
             }
 
-            Ok(Token::tokenize(&s))
-        } else {
-            Err(Error::NoMoreTokens)
+            if !self.next_line() {
+                return Err(Error::NoMoreTokens)
+            }
         }
     }
 
     pub fn next_word(&mut self) -> Result<String, Error> {
-        match self.next()? {
-            Token::Word(word) => Ok(word),
-            _ => Err(Error::InvalidWord)
-        }
+        self.next().map(|token| token.word)
     }
 
+    /**
+     * Reads a single character.  The end of each line reads as a newline, after which the next line has been read in.
+     */
     pub fn next_char(&mut self) -> Result<char, Error> {
-        self.stream.next().ok_or(Error::NoMoreTokens)
+        let source = self.current_mut();
+        if let Some(c) = source.rest().chars().next() {
+            source.position += c.len_utf8();
+            return Ok(c)
+        }
+
+        let line_ended = source.filled;
+        if !self.next_line() {
+            Err(Error::NoMoreTokens)
+        } else if line_ended {
+            Ok('\n')
+        } else {
+            self.next_char()
+        }
+    }
+}
+
+// the input buffer, read only
+impl MemorySegment for TokenStream<'_> {
+    fn get_base(&self) -> Address {
+        self.base
+    }
+
+    fn get_end(&self) -> Address {
+        self.base.plus(Bytes::bytes(self.current().buffer.len()))
+    }
+
+    fn write_value(&mut self, _address: Address, _value: Value) -> ForthResult {
+        Err(Error::InsufficientPermissions)
+    }
+
+    fn read_value(&self, address: Address) -> Result<Value, Error> {
+        self.check_address(address)?;
+        let start = address.offset_from(self.base).get_bytes() / CELL_SIZE * CELL_SIZE;
+        let mut bytes = [0; CELL_SIZE];
+        for (i, byte) in self.current().buffer.bytes().skip(start).take(CELL_SIZE).enumerate() {
+            bytes[i] = byte;
+        }
+        Ok(Number::from_le_bytes(bytes).value())
+    }
+
+    fn write_values(&mut self, _address: Address, _values: &[Value]) -> ForthResult {
+        Err(Error::InsufficientPermissions)
+    }
+
+    fn read_values(&self, address: Address, len: Cells) -> Result<Vec<Value>, Error> {
+        (0..len.get_cells()).map(|i| self.read_value(address.plus_cell(Cells::cells(i)))).collect()
     }
 
-    pub fn prepend_stream<I: Iterator<Item = char> + 'a>(&mut self, new_stream: I) {
-        let current_stream = std::mem::replace(&mut self.stream, Box::new(std::iter::empty()));
-        self.stream = Box::new(new_stream.chain(current_stream))
+    fn read_byte(&self, address: Address) -> Result<UnsignedByte, Error> {
+        self.check_address(address)?;
+        Ok(self.current().buffer.as_bytes()[address.offset_from(self.base).get_bytes()])
     }
 
-    // todo: implement some sort of next_char function
+    fn write_byte(&mut self, _address: Address, _byte: UnsignedByte) -> ForthResult {
+        Err(Error::InsufficientPermissions)
+    }
 }
 
+/**
+ * A word read from the input.  Words are looked up in the dictionary before they are read as numbers, so that a word
+ * spelled with the digits of the current base can still be called, which means the base has to come along with the word.
+ */
 #[derive(Debug)]
-pub enum Token {
-    Integer(generic_numbers::Number),
-    Word(String),
+pub struct Token {
+    pub word: String,
+    radix: u32,
 }
 
 impl Token {
-    pub fn tokenize(s: &str) -> Self {
-        fn parse_number(s: &str) -> Option<generic_numbers::Number> {
-            s.strip_prefix("0x").and_then(|x| generic_numbers::Number::from_str_radix(x, 16).ok())
-            .or_else(|| s.strip_prefix("0b").and_then(|x| generic_numbers::Number::from_str_radix(x, 2).ok()))
-            .or_else(|| s.parse::<generic_numbers::Number>().ok())
-        }
+    pub fn new(word: &str, radix: u32) -> Self {
+        Self { word: word.to_string(), radix }
+    }
 
-        parse_number(s).map_or_else(|| Token::Word(s.to_uppercase()), |i| Token::Integer(i))
+    pub fn number(&self) -> Option<generic_numbers::Number> {
+        let s = self.word.as_str();
+        s.strip_prefix("0x").and_then(|x| generic_numbers::Number::from_str_radix(x, 16).ok())
+            .or_else(|| s.strip_prefix("0b").and_then(|x| generic_numbers::Number::from_str_radix(x, 2).ok()))
+            .or_else(|| generic_numbers::Number::from_str_radix(s, self.radix).ok())
     }
 }
+
+#[test]
+fn input_source_test() {
+    let mut tokens = TokenStream::new("one two\r\n  three\n( x".chars()).with_base(Address::from_raw(Bytes::bytes(0x1000)));
+    assert_eq!(tokens.next_word(), Ok("one".to_string()));
+    assert_eq!(tokens.position(), 4);
+    assert_eq!(tokens.source(), (Address::from_raw(Bytes::bytes(0x1000)), Bytes::bytes(7)));
+    assert_eq!(tokens.read_byte(Address::from_raw(Bytes::bytes(0x1004))), Ok(b't'));
+
+    // the end of the line reads as a newline, and the carriage return is gone
+    assert_eq!(tokens.parse_name(), (Address::from_raw(Bytes::bytes(0x1004)), Bytes::bytes(3)));
+    assert_eq!(tokens.next_char(), Ok('\n'));
+    assert_eq!(tokens.line(), 1);
+    assert_eq!(tokens.parse('e'), (Address::from_raw(Bytes::bytes(0x1000)), Bytes::bytes(5)));
+
+    // an evaluated string runs before the rest of the line
+    tokens.push_string("four".to_string());
+    assert_eq!(tokens.source_id(), -1);
+    assert!(!tokens.refill());
+    assert_eq!(tokens.next_word(), Ok("four".to_string()));
+    assert_eq!(tokens.next_word(), Ok("e".to_string()));
+    assert_eq!(tokens.source_id(), 0);
+    assert_eq!(tokens.line(), 2);
+
+    // input can only be restored on the same line
+    let saved = tokens.save();
+    assert_eq!(tokens.next_word(), Ok("(".to_string()));
+    assert!(tokens.restore(&saved).is_err());
+    let saved = tokens.save();
+    assert_eq!(tokens.next_word(), Ok("x".to_string()));
+    assert!(tokens.restore(&saved).is_ok());
+    assert_eq!(tokens.next_word(), Ok("x".to_string()));
+    assert!(tokens.next().is_err());
+}
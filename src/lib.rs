@@ -2,11 +2,15 @@ mod operations;
 mod evaluate;
 mod environment;
 mod io;
+#[cfg(feature = "debugging")]
 mod debugging;
 mod compiled_instructions;
+mod optimizer;
 
 pub use evaluate::{kernels, config, Error, ForthResult, ForthState, Forth, definition::ExecutionToken};
-pub use environment::{generic_numbers::Number, stack, memory, value::Value};
+pub use environment::{generic_numbers::Number, stack, memory, units, io_ports, value::Value};
 pub use io::output_stream;
-pub use debugging::debugger;
-pub use debugging::profiler;
\ No newline at end of file
+#[cfg(feature = "debugging")]
+pub use debugging::{debugger, profiler, coverage, trace};
+#[cfg(feature = "repl")]
+pub use io::repl;
\ No newline at end of file
@@ -1,5 +1,4 @@
 use super::*;
-use crate::io::tokens;
 use crate::evaluate::definition;
 
 
@@ -9,6 +8,12 @@ pub fn set_compile(state: &mut ForthState) -> ForthResult { state.set_compilemod
 
 pub fn start_word_compilation(state: &mut ForthState) -> ForthResult {
     let word = state.input_stream.next_word()?;
+    // names that are decimal numbers can't be redefined, but ones that only read as numbers in some other base can be
+    if crate::io::tokens::Token::new(&word, 10).number().is_some() {
+        return Err(evaluate::Error::InvalidWord)
+    }
+
+    state.begin_definition();
 
     /*
      * Add space before the definition begins to hold metadata.
@@ -31,9 +36,6 @@ pub fn end_word_compilation(state: &mut ForthState) -> ForthResult {
     postpone!(state, super::stack_operations::pop_stack_frame);
     postpone!(state, super::control_flow_operations::control_flow_break);
 
-    // clear any declared temp values
-    state.definitions.clear_temp();
-
     // add in the length of the function at the beginning (leave space)
     if let evaluate::definition::ExecutionToken::Definition(address) = state.definitions.most_recent_definition().execution_token {
         let length_address = address.minus_cell(Cells::one());
@@ -42,6 +44,13 @@ pub fn end_word_compilation(state: &mut ForthState) -> ForthResult {
         state.data_space.write(length_address, length)?;
     }
 
+    // optimize the finished definition, while its locals are still known
+    crate::optimizer::optimize_definition(state)?;
+
+    // clear any declared temp values
+    state.definitions.clear_temp();
+    state.end_definition();
+
     set_interpret(state)
 }
 
@@ -113,6 +122,13 @@ pub fn body(state: &mut ForthState) -> ForthResult {
     Ok(())
 }
 
+// the inverse of >BODY, which turns the address of some code, such as a copy of a definition, into something that can be executed
+pub fn body_to_execution_token(state: &mut ForthState) -> ForthResult {
+    let address = state.stack.pop()?;
+    state.stack.push(evaluate::definition::ExecutionToken::Definition(address));
+    Ok(())
+}
+
 pub fn absorb_comment<T: closing_tokens::ClosingToken>(state: &mut ForthState) -> ForthResult {
     while let Ok(c) = state.input_stream.next_char() {
         if c == T::CLOSING_TOKEN {
@@ -123,14 +139,25 @@ pub fn absorb_comment<T: closing_tokens::ClosingToken>(state: &mut ForthState) -
     Err(evaluate::Error::NoMoreTokens)
 }
 
+pub fn line_comment(state: &mut ForthState) -> ForthResult {
+    state.input_stream.skip_line();
+    Ok(())
+}
+
+// leaves the system, which the host sees as an error carrying the exit code
+pub fn bye(_: &mut ForthState) -> ForthResult {
+    Err(evaluate::Error::Bye(0))
+}
+
+pub fn bye_with_code(state: &mut ForthState) -> ForthResult {
+    Err(evaluate::Error::Bye(state.stack.pop()?))
+}
+
 pub fn locals<T: closing_tokens::ClosingToken>(state: &mut ForthState) -> ForthResult {
     // read in all of the locals
     let mut local_names = Vec::new();
     loop {
-        let name = match state.input_stream.next()? {
-            tokens::Token::Word(name) => name,
-            _ => return Err(evaluate::Error::InvalidWord)
-        };
+        let name = state.input_stream.next_word()?;
 
         // reached the end of the list of locals
         if name.len() == 1 && name.chars().next().unwrap() == T::CLOSING_TOKEN {
@@ -180,37 +207,62 @@ pub fn locals<T: closing_tokens::ClosingToken>(state: &mut ForthState) -> ForthR
 
 pub fn compile_xt(state: &mut ForthState) -> ForthResult {
     let xt = state.stack.pop()?;
-    let mut copied = false;
-
-    if let definition::ExecutionToken::Definition(addr) = xt {
-        let length = state.data_space.read::<Bytes>(addr.minus_cell(Cells::one()))?;
-        if length < Bytes::bytes(state.config().definition_copy_threshold) {
-            // get the number of cells to copy
-            let num_cells = length.to_cells();
-            // get the address of the top of the data space, which is where the inlined definition should be copied
-            let top = state.data_space.top();
-            // clear space to copy the inlined definition
-            state.data_space.expand(num_cells);
-            // read and then write the inlined definition
-            let inlined = state.data_space.read_values(addr, num_cells)?;
-            state.data_space.write_values(top, &inlined[..])?;
-            copied = true;
-        }
-        // make the return stack construction / destruction into instructions, so that the relocation doesn't interfere with it     
-    }
+    // compile it the same way the compiler would, inlining it if its small enough
+    crate::optimizer::compile(state, xt)
+}
 
-    // if the execution topen was not copied / inlined, then call it directly
-    if !copied {
-        state.data_space.push(xt)        
+fn set_inline_hint(state: &mut ForthState, hint: crate::optimizer::InlineHint) -> ForthResult {
+    if let definition::ExecutionToken::Definition(address) = state.definitions.most_recent_definition().execution_token {
+        state.optimizer.set_inline_hint(address, hint);
     }
 
     Ok(())
 }
 
+pub fn inline(state: &mut ForthState) -> ForthResult { set_inline_hint(state, crate::optimizer::InlineHint::Always) }
+pub fn no_inline(state: &mut ForthState) -> ForthResult { set_inline_hint(state, crate::optimizer::InlineHint::Never) }
+
 pub fn execution_mode_address(state: &mut ForthState) -> ForthResult {
     Ok(state.stack.push(state.internal_state_memory().execution_mode.address))
 }
 
+pub fn number_base_address(state: &mut ForthState) -> ForthResult {
+    state.stack.push(state.internal_state_memory().number_base.address);
+    Ok(())
+}
+
+pub fn input_position_address(state: &mut ForthState) -> ForthResult {
+    state.stack.push(state.internal_state_memory().input_position.address);
+    Ok(())
+}
+
+// pushes the value of a register, rather than its address
+fn push_register(state: &mut ForthState, name: &str) -> ForthResult {
+    let register = state.internal_state_memory().get_register(name).ok_or(evaluate::Error::UnknownWord(name.to_string()))?;
+    let value = register.read(state);
+    state.stack.push(value);
+    Ok(())
+}
+
+pub fn depth(state: &mut ForthState) -> ForthResult { push_register(state, "DEPTH") }
+pub fn source_id(state: &mut ForthState) -> ForthResult { push_register(state, "SOURCE-ID") }
+
+pub fn environment_query(state: &mut ForthState) -> ForthResult {
+    let length: Bytes = state.stack.pop()?;
+    let address: memory::Address = state.stack.pop()?;
+    let name = String::from_utf8_lossy(&state.read_bytes(address, length)?).to_string();
+
+    // unknown queries only push false
+    let answer = state.environment(&name).cloned();
+    if let Some(values) = &answer {
+        for value in values {
+            state.stack.push(*value);
+        }
+    }
+    state.stack.push(answer.is_some() as generic_numbers::Number);
+    Ok(())
+}
+
 pub fn get_operations() -> Vec<(&'static str, bool, super::Operation)> {
     vec![
         ("IMMEDIATE", false, immedate),
@@ -223,10 +275,18 @@ pub fn get_operations() -> Vec<(&'static str, bool, super::Operation)> {
         ("EXECUTE", false, execute),
         ("'", false, read_execution_token),
         (">BODY", false, body),
+        ("BODY>", false, body_to_execution_token),
         ("[']", true, get_execution_token),
         ("(", true, absorb_comment::<closing_tokens::Parenthesis>),
-        ("\\", true, absorb_comment::<closing_tokens::NewLine>),
+        ("\\", true, line_comment),
         ("STATE", false, execution_mode_address),
+        ("BASE", false, number_base_address),
+        (">IN", false, input_position_address),
+        ("DEPTH", false, depth),
+        ("SOURCE-ID", false, source_id),
+        ("ENVIRONMENT?", false, environment_query),
+        ("BYE", false, bye),
+        ("(BYE)", false, bye_with_code),
         // branch generators
         ("_BNE", false, write_branch_false),
         ("_B", false, write_branch),
@@ -234,7 +294,9 @@ pub fn get_operations() -> Vec<(&'static str, bool, super::Operation)> {
         ("LOCALS|", true, locals::<closing_tokens::Pipe>),
         ("{", true, locals::<closing_tokens::CurlyBracket>),
         // compilation words
-        ("COMPILE,", false, compile_xt)
+        ("COMPILE,", false, compile_xt),
+        ("INLINE", false, inline),
+        ("NOINLINE", false, no_inline)
     ]
 }
 
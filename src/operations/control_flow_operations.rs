@@ -126,16 +126,12 @@ pub fn throw(state: &mut evaluate::ForthState) -> evaluate::ForthResult {
 }
 
 pub fn evaluate_string(state: &mut evaluate::ForthState) -> evaluate::ForthResult {
-    let length: usize = state.stack.pop()?;
+    let length: Bytes = state.stack.pop()?;
     let address: memory::Address = state.stack.pop()?;
 
-    // read the characters into a vector
-    let mut copied_string = Vec::new();
-    for i in (0..length).map(|i| Bytes::from(i)) {
-        copied_string.push(state.read::<generic_numbers::UnsignedByte>(address.plus(i))? as char);
-    }
-    // convert that vector into an into_iter, which takes ownership of it, and prepend it to the current input stream
-    state.input_stream.prepend_stream(copied_string.into_iter());
+    // the string, read as utf-8, becomes the input source until it runs out
+    let copied_string = String::from_utf8_lossy(&state.read_bytes(address, length)?).to_string();
+    state.input_stream.push_string(copied_string);
 
     Ok(())
 }
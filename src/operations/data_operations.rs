@@ -6,6 +6,8 @@ use evaluate::definition;
 
 pub fn here(state: &mut evaluate::ForthState) -> evaluate::ForthResult { state.stack.push(state.data_space.top().to_number()); Ok(()) }
 
+pub fn pad(state: &mut evaluate::ForthState) -> evaluate::ForthResult { state.stack.push(state.pad_address()); Ok(()) }
+
 pub fn allot(state: &mut evaluate::ForthState) -> evaluate::ForthResult { 
     state.data_space.expand(state.stack.pop::<Bytes>()?.to_cells()); 
     Ok(()) 
@@ -116,9 +118,71 @@ pub fn map_anonymous(state: &mut evaluate::ForthState) -> evaluate::ForthResult
     Ok(())
 }
 
+// maps the file named by a string, with flags giving its permissions like protect.  writable files are copy-on-write
+pub fn map_file(state: &mut evaluate::ForthState) -> evaluate::ForthResult {
+    let permissions = memory::MemoryPermissions::from_bits(state.stack.pop()?);
+    let length = state.stack.pop::<Bytes>()?;
+    let address = state.stack.pop()?;
+    let path = String::from_utf8_lossy(&state.read_bytes(address, length)?).into_owned();
+
+    match state.map_file(path, permissions) {
+        Ok(address) => {
+            state.stack.push(address);
+            state.stack.push(0 as generic_numbers::Number);
+        },
+        Err(_) => {
+            state.stack.push(0 as generic_numbers::Number);
+            state.stack.push(-1 as generic_numbers::Number);
+        }
+    }
+
+    Ok(())
+}
+
+pub fn unmap(state: &mut evaluate::ForthState) -> evaluate::ForthResult {
+    let num_pages = state.stack.pop()?;
+    let address = state.stack.pop()?;
+    state.unmap_mapping(address, num_pages)
+}
+
+// permissions are given as bits: read = 1, write = 2, execute = 4
+pub fn protect(state: &mut evaluate::ForthState) -> evaluate::ForthResult {
+    let permissions = memory::MemoryPermissions::from_bits(state.stack.pop()?);
+    let num_pages = state.stack.pop()?;
+    let address = state.stack.pop()?;
+    state.protect_mapping(address, num_pages, permissions)
+}
+
+// pushes the base and permissions of the mapping containing the address, or two zeros if it isn't mapped
+pub fn query_mapping(state: &mut evaluate::ForthState) -> evaluate::ForthResult {
+    let address = state.stack.pop()?;
+    match state.query_mapping(address) {
+        Some(mapping) => {
+            state.stack.push(mapping.base);
+            state.stack.push(mapping.permissions.to_bits());
+        },
+        None => {
+            state.stack.push(0 as generic_numbers::Number);
+            state.stack.push(0 as generic_numbers::Number);
+        }
+    }
+
+    Ok(())
+}
+
+// remembers which word allocated a chunk, for the heap report
+fn record_allocation_origin(state: &mut evaluate::ForthState, address: memory::Address) {
+    if state.heap.diagnostics_enabled() {
+        let instruction_pointer = state.instruction_pointer();
+        let word = instruction_pointer.and_then(|address| state.definitions.debug_only_get_containing_name(address));
+        state.heap.set_origin(address, word, instruction_pointer);
+    }
+}
+
 pub fn allocate(state: &mut evaluate::ForthState) -> evaluate::ForthResult {
     match state.heap.allocate(state.stack.pop::<Bytes>()?) {
         Ok(address) => {
+            record_allocation_origin(state, address);
             state.stack.push(address);
             state.stack.push(0 as generic_numbers::Number);
         },
@@ -135,6 +199,8 @@ pub fn free(state: &mut evaluate::ForthState) -> evaluate::ForthResult {
     let address: memory::Address = state.stack.pop()?;
     match state.heap.free(address) {
         Ok(_) => state.stack.push(0 as generic_numbers::Number),
+        // a double free is a bug in the program, rather than something it should handle
+        Err(evaluate::Error::DoubleFree) => return Err(evaluate::Error::DoubleFree),
         Err(_) => state.stack.push(-1 as generic_numbers::Number),
     }
 
@@ -146,9 +212,14 @@ pub fn resize(state: &mut evaluate::ForthState) -> evaluate::ForthResult {
     let address: memory::Address = state.stack.pop()?;
     match state.heap.resize(address, new_size) {
         Ok(new_address) => {
+            if new_address != address {
+                record_allocation_origin(state, new_address);
+            }
             state.stack.push(new_address);
             state.stack.push(0 as generic_numbers::Number);
         },
+        // like FREE, resizing a chunk that was already freed is a bug in the program
+        Err(evaluate::Error::UseAfterFree) => return Err(evaluate::Error::UseAfterFree),
         Err(_) => {
             state.stack.push(0 as generic_numbers::Number);
             state.stack.push(-1 as generic_numbers::Number);
@@ -158,9 +229,16 @@ pub fn resize(state: &mut evaluate::ForthState) -> evaluate::ForthResult {
     Ok(())
 }
 
+pub fn heap_report(state: &mut evaluate::ForthState) -> evaluate::ForthResult {
+    let report = state.heap.report();
+    state.output_stream.writeln(&report);
+    Ok(())
+}
+
 pub fn get_operations() -> Vec<(&'static str, bool, super::Operation)> {
     vec![
         ("HERE", false, here),
+        ("PAD", false, pad),
         ("ALLOT", false, allot),
         ("CREATE", false, create),
         ("DOES>", false, does),
@@ -168,11 +246,16 @@ pub fn get_operations() -> Vec<(&'static str, bool, super::Operation)> {
         ("CELLS", false, cells),
         ("TO", true, to),
         ("MAP", false, map_anonymous), 
+        ("MAP-FILE", false, map_file),
+        ("UNMAP", false, unmap),
+        ("PROTECT", false, protect),
+        ("MAPPING?", false, query_mapping),
 
         // heap instructions
         ("ALLOCATE", false, allocate),
         ("FREE", false, free),
         ("RESIZE", false, resize),        
+        ("HEAP-REPORT", false, heap_report),
 
         ("VARIABLE" , false, variable::<value::Value>),
         ("CONSTANT", false, constant::<value::Value>),
@@ -180,3 +263,44 @@ pub fn get_operations() -> Vec<(&'static str, bool, super::Operation)> {
         ("2CONSTANT", false, constant::<value::DoubleValue>)
     ]
 }
+
+#[cfg(test)]
+fn run<'i>(f: &mut evaluate::Forth<'_, 'i, '_, evaluate::kernels::DefaultKernel>, source: &'i str) -> Result<Vec<generic_numbers::Number>, evaluate::Error> {
+    f.evaluate_string(source)?;
+    let results = f.state.stack.to_vec().iter().map(|value| value.to_number()).collect();
+    f.state.stack = crate::environment::stack::Stack::new(f.state.config().stack_addr);
+    Ok(results)
+}
+
+#[test]
+fn map_file_test() {
+    let path = std::env::temp_dir().join(format!("forth_map_file_test_{}", std::process::id()));
+    let contents = (0..10000).map(|i| (i % 7) as u8).collect::<Vec<_>>();
+    std::fs::write(&path, &contents).unwrap();
+
+    // the path goes in the first anonymous page, and the file gets mapped just after it
+    let path_bytes = path.to_str().unwrap().as_bytes();
+    let path_address = memory::Address::from_raw(Bytes::bytes(evaluate::config::ForthConfig::default().anonymous_mappings_addr));
+    let file = path_address.plus(Bytes::bytes(memory::PAGE_SIZE)).as_raw() as generic_numbers::Number;
+    let map = |flags| format!("{} {} {} map-file", path_address.as_raw(), path_bytes.len(), flags);
+    let read_only = format!("{}  swap constant file  : sum  0 10000 0 do file i + c@ + loop ;  file mapping?  file 9999 + c@  sum", map(1));
+    let copy_on_write = format!("{}  drop constant copy  5 copy c!  copy c@  file c@", map(3));
+    let missing = map(1);
+
+    let mut f = evaluate::Forth::default();
+    assert!(run(&mut f, "1 map drop").is_ok());
+    assert!(f.state.write_bytes(path_address, path_bytes).is_ok());
+
+    // a read only mapping, scanned in place
+    assert_eq!(run(&mut f, &read_only), Ok(vec![0, file, 1, 9999 % 7, contents.iter().map(|byte| *byte as generic_numbers::Number).sum()]));
+    assert_eq!(run(&mut f, "5 file c!"), Err(evaluate::Error::InsufficientPermissions));
+    assert_eq!(run(&mut f, "file 10000 + c@"), Err(evaluate::Error::InvalidAddress));
+
+    // a copy-on-write mapping sees its own changes, but leaves the file alone
+    assert_eq!(run(&mut f, &copy_on_write), Ok(vec![5, 0]));
+    assert_eq!(std::fs::read(&path).unwrap(), contents);
+
+    assert_eq!(run(&mut f, "file 3 unmap  file mapping?  copy c@"), Ok(vec![0, 0, 5]));
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(run(&mut f, &missing), Ok(vec![0, -1]));
+}
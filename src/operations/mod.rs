@@ -7,9 +7,10 @@ mod arithmetic_operations;
 mod compiler_control_operations;
 mod data_operations;
 mod memory_operations;
-mod print_operations;
-mod stack_operations;
+pub mod print_operations;
+pub mod stack_operations;
 mod string_operations;
+mod xchar_operations;
 
 // import all of the macros exposed by this module for ease of use by the other operations modules
 use crate::postpone;
@@ -66,7 +67,6 @@ mod closing_tokens {
     }
 
     closing_token!(CurlyBracket, '}');
-    closing_token!(NewLine, '\n');
     closing_token!(Parenthesis, ')');
     closing_token!(Pipe, '|');
 }
@@ -89,9 +89,23 @@ pub fn get_operations() -> OperationTable {
         compiler_control_operations::get_operations(),
         print_operations::get_operations(),
         string_operations::get_operations(),
+        xchar_operations::get_operations(),
     ].into_iter().flatten().collect::<Vec<_>>()
 }
 
+/**
+ * Whether an operation is compiled with a counted string right after it, which it jumps over when it runs.
+ */
+pub fn has_inline_string(operation: Operation) -> bool {
+    let operations: [Operation; 4] = [
+        print_operations::print_string_literal,
+        string_operations::string_literal,
+        string_operations::counted_string_literal,
+        string_operations::abort_literal,
+    ];
+    operations.iter().any(|inline_operation| *inline_operation as usize == operation as usize)
+}
+
 /**
  * For the sake of demonstration, some important words, including IF ELSE THEN, are implemented
  * in FORTH instead of hardcoded.  Most of the important words can be implemented from only
@@ -109,5 +123,9 @@ pub const UNCOMPILED_OPERATIONS: &[&str] = &[
     ": [CHAR] CHAR POSTPONE LITERAL ; IMMEDIATE",
     // some increment instructions
     ": CELL+ 1 CELLS + ;",
-    ": 1+ 1 + ;"
+    ": 1+ 1 + ;",
+
+    // number bases
+    ": DECIMAL 10 BASE ! ;",
+    ": HEX 16 BASE ! ;"
 ];
\ No newline at end of file
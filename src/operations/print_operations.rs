@@ -2,7 +2,8 @@ use super::*;
 
 
 pub fn pop_and_print<N: GenericNumber>(state: &mut evaluate::ForthState) -> evaluate::ForthResult {
-    state.output_stream.write(&format!("{:?} ", state.stack.pop::<N>()?));
+    let number = state.stack.pop::<N>()?;
+    state.output_stream.write(&format!("{} ", number.to_string_in_radix(state.input_stream.radix())));
     Result::Ok(())
 }
 
@@ -11,23 +12,45 @@ pub fn print_newline(state: &mut evaluate::ForthState) -> evaluate::ForthResult
     Result::Ok(())
 }
 
-pub fn print_string(state: &mut evaluate::ForthState) -> evaluate::ForthResult {
-    state.data_space.push(evaluate::definition::ExecutionToken::LeafOperation(|state| {
-        // there must be an instruction pointer if its literally executing this
-        let mut string_address = state.instruction_pointer().unwrap();
-        let length: generic_numbers::UnsignedByte = state.read(string_address)?;
-        for _ in 0..length {
-            // increment the string address and read the next character
-            string_address.increment();
-            let c: generic_numbers::UnsignedByte = state.read(string_address)?;
-            // print the byte as a character
-            state.output_stream.write(&format!("{}", c as char));
+/**
+ * Writes bytes to the output as utf-8.  A character that is cut off at the end is held back until the rest of it is written,
+ * so EMITting the bytes of a character one at a time prints the whole character.  Bytes that aren't part of any character
+ * print as the replacement character.
+ */
+pub fn write_output_bytes(state: &mut evaluate::ForthState, bytes: &[generic_numbers::UnsignedByte]) {
+    let mut pending = std::mem::take(&mut state.partial_character);
+    pending.extend_from_slice(bytes);
+
+    // look back for the first byte of the last character, to see if all of it is there
+    let mut complete = pending.len();
+    for (i, byte) in pending.iter().enumerate().rev().take(3) {
+        if byte & 0xc0 != 0x80 {
+            if i + xchar_operations::utf8_length(*byte) > pending.len() {
+                complete = i;
+            }
+            break
         }
+    }
 
-        // now jump to the next instruction
-        state.jump_to(string_address.nearest_cell())
+    state.partial_character = pending.split_off(complete);
+    if !pending.is_empty() {
+        state.output_stream.write(&String::from_utf8_lossy(&pending));
+    }
+}
 
-    }).value());
+pub fn print_string_literal(state: &mut evaluate::ForthState) -> evaluate::ForthResult {
+    // there must be an instruction pointer if its literally executing this
+    let string_address = state.instruction_pointer().unwrap();
+    let length = Bytes::bytes(state.read::<generic_numbers::UnsignedByte>(string_address)? as usize);
+    let bytes = state.read_bytes(string_address.plus(Bytes::one()), length)?;
+    write_output_bytes(state, &bytes);
+
+    // now jump to the next instruction
+    state.jump_to(string_address.plus(length + Bytes::one()).nearest_cell())
+}
+
+pub fn print_string(state: &mut evaluate::ForthState) -> evaluate::ForthResult {
+    state.data_space.push(evaluate::definition::ExecutionToken::LeafOperation(print_string_literal).value());
 
     string_operations::read_string_to_memory(state, '"')
 }
@@ -36,17 +59,14 @@ pub fn type_string(state: &mut evaluate::ForthState) -> evaluate::ForthResult {
     let count: Bytes = state.stack.pop()?;
     let address: memory::Address = state.stack.pop()?;
 
-    for i in (0..count.get_bytes()).map(|i| Bytes::from(i)) {
-        let c: generic_numbers::UnsignedByte = state.read(address.plus(i))?;
-        state.output_stream.write(&format!("{}", c as char));
-    }
-
+    let bytes = state.read_bytes(address, count)?;
+    write_output_bytes(state, &bytes);
     Ok(())
 }
 
 pub fn emit(state: &mut evaluate::ForthState) -> evaluate::ForthResult {
-    let ascii_char = state.stack.pop::<generic_numbers::UnsignedByte>()? as char;
-    state.output_stream.write(&format!("{}", ascii_char));
+    let byte = state.stack.pop::<generic_numbers::UnsignedByte>()?;
+    write_output_bytes(state, &[byte]);
     Ok(())
 }
 
@@ -1,33 +1,29 @@
+use std::convert::TryFrom;
+
 use super::*;
+use crate::evaluate::{Error, ExecutionMode, definition};
+use crate::environment::generic_numbers::UnsignedByte;
 
 
 pub fn get_char(state: &mut ForthState) -> ForthResult {
     let c = state.input_stream.next_char()?;
 
-    state.stack.push(c as generic_numbers::Byte);
+    // characters past ascii are given as their code point, like XKEY
+    state.stack.push(c as generic_numbers::Number);
     Ok(())
 }
 
 pub fn read_string_to_memory(state: &mut ForthState, delimiter: char) -> ForthResult {
-    let length_address = state.data_space.top();
-    let mut string_address = length_address.plus(Bytes::one());
-    let mut length: generic_numbers::UnsignedByte = 0;
+    let mut bytes = Vec::new();
     loop {
         let next_char = state.input_stream.next_char()?;
         if next_char == delimiter {
             break;
-        } else {
-            if !string_address.less_than(state.data_space.top()) {
-                state.data_space.push_none::<value::Value>();
-            }
-            state.write(string_address, next_char as generic_numbers::UnsignedByte)?;
-            string_address.increment();
-            length += 1;
         }
+        bytes.extend_from_slice(next_char.encode_utf8(&mut [0; 4]).as_bytes());
     }
-    state.write(length_address, length)?;
 
-    Ok(())
+    compile_counted_string(state, &bytes)
 }
 
 pub fn get_word(state: &mut ForthState) -> ForthResult {
@@ -54,65 +50,413 @@ pub fn trailing(state: &mut ForthState) -> ForthResult {
     Ok(())
 }
 
+// copies one byte at a time, in the given order of offsets, between overlapping ranges.  the copy is worked out on the
+// bytes covering both ranges first, so that a range that doesn't fit fails before anything has been written
+fn copy_each_byte(state: &mut ForthState, source: memory::Address, destination: memory::Address, count: Bytes, offsets: impl Iterator<Item = usize>) -> ForthResult {
+    let start = if source.less_than(destination) { source } else { destination };
+    let (from, to) = (source.offset_from(start).get_bytes(), destination.offset_from(start).get_bytes());
+    let span = count.get_bytes().checked_add(from.max(to)).ok_or(evaluate::Error::InvalidAddress)?;
+
+    let mut bytes = state.read_bytes(start, Bytes::bytes(span))?;
+    for i in offsets {
+        bytes[to + i] = bytes[from + i];
+    }
+    state.write_bytes(destination, &bytes[to..to + count.get_bytes()])
+}
+
 pub fn cmove(state: &mut ForthState) -> ForthResult {
-    let count: usize = state.stack.pop()?;
+    let count: Bytes = state.stack.pop()?;
     let destination: memory::Address = state.stack.pop()?;
     let source: memory::Address = state.stack.pop()?;
 
-    for i in (0..count).map(|i| Bytes::from(i)) {
-        let current_byte = state.read::<Bytes>(source.plus(i))?;
-        state.write(destination.plus(i), current_byte)?;
+    // copying from low to high smears the start of the source over a destination just above it, which a bulk copy won't do
+    if source.less_than(destination) && destination.offset_from(source) < count {
+        copy_each_byte(state, source, destination, count, 0..count.get_bytes())
+    } else {
+        state.copy_bytes(source, destination, count)
     }
-
-    Ok(())
 }
 
 pub fn cmove_backwards(state: &mut ForthState) -> ForthResult {
-    let count: usize = state.stack.pop()?;
+    let count: Bytes = state.stack.pop()?;
     let destination: memory::Address = state.stack.pop()?;
     let source: memory::Address = state.stack.pop()?;
 
-    for i in ((count - 1)..0).map(|i| Bytes::from(i)) {
-        let current_byte = state.read::<generic_numbers::UnsignedByte>(source.plus(i))?;
-        state.write(destination.plus(i), current_byte)?;
+    if destination.less_than(source) && source.offset_from(destination) < count {
+        copy_each_byte(state, source, destination, count, (0..count.get_bytes()).rev())
+    } else {
+        state.copy_bytes(source, destination, count)
     }
-
-    Ok(())
 }
 
 pub fn move_noclobber(state: &mut ForthState) -> ForthResult {
-    let count: usize = state.stack.pop()?;
+    let count: Bytes = state.stack.pop()?;
     let destination: memory::Address = state.stack.pop()?;
     let source: memory::Address = state.stack.pop()?;
     
-    let mut bytes = Vec::new();
-    for i in (0..count).map(|i| Bytes::from(i)) {
-        bytes.push(state.read::<generic_numbers::UnsignedByte>(source.plus(i))?);
-    }
+    state.copy_bytes(source, destination, count)
+}
 
-    for (i, byte) in bytes.into_iter().enumerate() {
-        state.write(destination.plus(Bytes::from(i)), byte)?;
-    }
+pub fn fill(state: &mut ForthState) -> ForthResult {
+    let byte: generic_numbers::UnsignedByte = state.stack.pop()?;
+    let count: Bytes = state.stack.pop()?;
+    let address: memory::Address = state.stack.pop()?;
 
-    Ok(())
+    state.fill_bytes(address, count, byte)
 }
 
 pub fn accept(state: &mut ForthState) -> ForthResult {
     let count: Bytes = state.stack.pop()?;
     let address: memory::Address = state.stack.pop()?;
 
+    // characters are stored as utf-8, and one that doesn't fit in what's left of the buffer ends the input
     let mut copied_characters = Bytes::zero();
     while copied_characters < count {
         let current_char = state.input_stream.next_char()?;
-        
-        if current_char == '\n' {
+        let mut encoded = [0; 4];
+        let bytes = current_char.encode_utf8(&mut encoded).as_bytes();
+        if current_char == '\n' || copied_characters + Bytes::bytes(bytes.len()) > count {
             break;
         }
 
-        state.write(address.plus(copied_characters), current_char as generic_numbers::UnsignedByte)?;
-        copied_characters += Bytes::one();
+        state.write_bytes(address.plus(copied_characters), bytes)?;
+        copied_characters += Bytes::bytes(bytes.len());
+    }
+
+    state.stack.push(copied_characters);
+    Ok(())
+}
+
+pub fn source(state: &mut ForthState) -> ForthResult {
+    let (address, length) = state.input_stream.source();
+    state.stack.push(address);
+    state.stack.push(length);
+    Ok(())
+}
+
+pub fn refill(state: &mut ForthState) -> ForthResult {
+    let refilled = state.input_stream.refill();
+    state.stack.push(refilled as generic_numbers::Number);
+    Ok(())
+}
+
+pub fn parse(state: &mut ForthState) -> ForthResult {
+    let delimiter = state.stack.pop::<generic_numbers::UnsignedByte>()? as char;
+    let (address, length) = state.input_stream.parse(delimiter);
+    state.stack.push(address);
+    state.stack.push(length);
+    Ok(())
+}
+
+pub fn parse_name(state: &mut ForthState) -> ForthResult {
+    let (address, length) = state.input_stream.parse_name();
+    state.stack.push(address);
+    state.stack.push(length);
+    Ok(())
+}
+
+pub fn save_input(state: &mut ForthState) -> ForthResult {
+    let saved = state.input_stream.save();
+    let count = saved.len() as generic_numbers::Number;
+    for x in saved {
+        state.stack.push(x);
+    }
+    state.stack.push(count);
+    Ok(())
+}
+
+// the flag is true when the input couldn't be restored
+pub fn restore_input(state: &mut ForthState) -> ForthResult {
+    let count: usize = state.stack.pop()?;
+    let mut saved = (0..count).map(|_| state.stack.pop::<generic_numbers::Number>()).collect::<Result<Vec<_>, _>>()?;
+    saved.reverse();
+    let failed = state.input_stream.restore(&saved).is_err();
+    state.stack.push(failed as generic_numbers::Number);
+    Ok(())
+}
+
+// the escapes S\" understands, other than \x, which is followed by two hex digits
+fn escaped_bytes(c: char) -> Option<&'static [UnsignedByte]> {
+    Some(match c {
+        'a' => &[7],
+        'b' => &[8],
+        'e' => &[27],
+        'f' => &[12],
+        'l' | 'n' => &[10],
+        'm' => &[13, 10],
+        'q' | '"' => b"\"",
+        'r' => &[13],
+        't' => &[9],
+        'v' => &[11],
+        'z' => &[0],
+        '\\' => b"\\",
+        _ => return None
+    })
+}
+
+fn unescape(text: &str) -> Result<Vec<UnsignedByte>, Error> {
+    let mut bytes = Vec::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+            continue
+        }
+
+        match chars.next() {
+            Some('x') => {
+                let digits = chars.by_ref().take(2).collect::<String>();
+                bytes.push(UnsignedByte::from_str_radix(&digits, 16).map_err(|_| Error::InvalidNumber)?);
+            },
+            Some(c) => bytes.extend_from_slice(escaped_bytes(c).ok_or(Error::InvalidWord)?),
+            None => return Err(Error::InvalidWord)
+        }
+    }
+
+    Ok(bytes)
+}
+
+// parses a string up to the closing quote, which can be escaped with a backslash when the string has escapes
+fn parse_quoted(state: &mut ForthState, escapes: bool) -> Result<Vec<UnsignedByte>, Error> {
+    if !escapes {
+        return Ok(state.input_stream.parse_text(|c| c == '"').into_bytes())
+    }
+
+    let mut escaped = false;
+    let text = state.input_stream.parse_text(|c| {
+        let delimiter = c == '"' && !escaped;
+        escaped = c == '\\' && !escaped;
+        delimiter
+    });
+    unescape(&text)
+}
+
+// counted strings can only be as long as their count byte can hold
+fn counted_length(bytes: &[UnsignedByte]) -> Result<UnsignedByte, Error> {
+    UnsignedByte::try_from(bytes.len()).map_err(|_| Error::InvalidSize)
+}
+
+// compiles a counted string into data space, padded out to a whole number of cells
+fn compile_counted_string(state: &mut ForthState, bytes: &[UnsignedByte]) -> ForthResult {
+    let length = counted_length(bytes)?;
+    let address = state.data_space.top();
+    state.data_space.expand(Bytes::bytes(bytes.len() + 1).to_cells());
+    state.write(address, length)?;
+    state.write_bytes(address.plus(Bytes::one()), bytes)
+}
+
+// the counted string compiled after the instruction being executed, which is then jumped over
+fn inline_string(state: &mut ForthState) -> Result<(memory::Address, Bytes), Error> {
+    let address = state.instruction_pointer().unwrap();
+    let length = Bytes::bytes(state.read::<UnsignedByte>(address)? as usize);
+    state.jump_to(address.plus(length + Bytes::one()).nearest_cell())?;
+    Ok((address.plus(Bytes::one()), length))
+}
+
+pub fn string_literal(state: &mut ForthState) -> ForthResult {
+    let (address, length) = inline_string(state)?;
+    state.stack.push(address);
+    state.stack.push(length);
+    Ok(())
+}
+
+pub fn counted_string_literal(state: &mut ForthState) -> ForthResult {
+    let (address, _) = inline_string(state)?;
+    state.stack.push(address.minus(Bytes::one()));
+    Ok(())
+}
+
+fn abort_if(state: &mut ForthState, message: Vec<UnsignedByte>) -> ForthResult {
+    if state.stack.pop::<generic_numbers::Number>()? != 0 {
+        Err(Error::Abort(String::from_utf8_lossy(&message).into_owned()))
+    } else {
+        Ok(())
+    }
+}
+
+pub fn abort_literal(state: &mut ForthState) -> ForthResult {
+    let (address, length) = inline_string(state)?;
+    let message = state.read_bytes(address, length)?;
+    abort_if(state, message)
+}
+
+fn string(state: &mut ForthState, escapes: bool) -> ForthResult {
+    let bytes = parse_quoted(state, escapes)?;
+    match state.execution_mode() {
+        ExecutionMode::Compile => {
+            state.data_space.push(definition::ExecutionToken::LeafOperation(string_literal));
+            compile_counted_string(state, &bytes)
+        },
+        ExecutionMode::Interpret => {
+            let address = state.transient_string(&bytes)?;
+            state.stack.push(address);
+            state.stack.push(Bytes::bytes(bytes.len()));
+            Ok(())
+        }
+    }
+}
+
+pub fn s_quote(state: &mut ForthState) -> ForthResult { string(state, false) }
+pub fn s_backslash_quote(state: &mut ForthState) -> ForthResult { string(state, true) }
+
+pub fn c_quote(state: &mut ForthState) -> ForthResult {
+    let bytes = parse_quoted(state, false)?;
+    match state.execution_mode() {
+        ExecutionMode::Compile => {
+            state.data_space.push(definition::ExecutionToken::LeafOperation(counted_string_literal));
+            compile_counted_string(state, &bytes)
+        },
+        ExecutionMode::Interpret => {
+            let address = state.transient_string(&[&[counted_length(&bytes)?], &bytes[..]].concat())?;
+            state.stack.push(address);
+            Ok(())
+        }
+    }
+}
+
+pub fn abort_quote(state: &mut ForthState) -> ForthResult {
+    let bytes = parse_quoted(state, false)?;
+    match state.execution_mode() {
+        ExecutionMode::Compile => {
+            state.data_space.push(definition::ExecutionToken::LeafOperation(abort_literal));
+            compile_counted_string(state, &bytes)
+        },
+        ExecutionMode::Interpret => abort_if(state, bytes)
     }
+}
+
+// pops a string given as an address and length
+fn pop_string(state: &mut ForthState) -> Result<(memory::Address, Bytes), Error> {
+    let length: Bytes = state.stack.pop()?;
+    let address: memory::Address = state.stack.pop()?;
+    Ok((address, length))
+}
+
+fn pop_string_bytes(state: &mut ForthState) -> Result<Vec<UnsignedByte>, Error> {
+    let (address, length) = pop_string(state)?;
+    state.read_bytes(address, length)
+}
+
+pub fn slash_string(state: &mut ForthState) -> ForthResult {
+    let n: generic_numbers::Number = state.stack.pop()?;
+    let length: generic_numbers::Number = state.stack.pop()?;
+    let address: memory::Address = state.stack.pop()?;
+
+    state.stack.push(address.to_number().wrapping_add(n));
+    state.stack.push(length.wrapping_sub(n));
+    Ok(())
+}
+
+pub fn blank(state: &mut ForthState) -> ForthResult {
+    let (address, length) = pop_string(state)?;
+    state.fill_bytes(address, length, b' ')
+}
+
+pub fn compare(state: &mut ForthState) -> ForthResult {
+    let second = pop_string_bytes(state)?;
+    let first = pop_string_bytes(state)?;
+
+    let ordering: generic_numbers::Number = match first.cmp(&second) {
+        std::cmp::Ordering::Less => -1,
+        std::cmp::Ordering::Equal => 0,
+        std::cmp::Ordering::Greater => 1
+    };
+    state.stack.push(ordering);
+    Ok(())
+}
+
+pub fn search(state: &mut ForthState) -> ForthResult {
+    let needle = pop_string_bytes(state)?;
+    let (address, length) = pop_string(state)?;
+    let haystack = state.read_bytes(address, length)?;
+
+    // an empty string is found at the start
+    let found = if needle.is_empty() {
+        Some(0)
+    } else {
+        haystack.windows(needle.len()).position(|window| window == &needle[..])
+    };
+
+    let offset = Bytes::bytes(found.unwrap_or(0));
+    state.stack.push(address.plus(offset));
+    state.stack.push(length - offset);
+    state.stack.push(found.is_some() as generic_numbers::Number);
+    Ok(())
+}
+
+pub fn sliteral(state: &mut ForthState) -> ForthResult {
+    let bytes = pop_string_bytes(state)?;
+    state.data_space.push(definition::ExecutionToken::LeafOperation(string_literal));
+    compile_counted_string(state, &bytes)
+}
+
+pub fn replaces(state: &mut ForthState) -> ForthResult {
+    let name = pop_string_bytes(state)?;
+    let text = pop_string_bytes(state)?;
+
+    let key = state.definitions.key(&String::from_utf8_lossy(&name));
+    state.substitutions.insert(key, text);
+    Ok(())
+}
+
+/**
+ * Replaces each %name% in the text with what was given to REPLACES for that name, and %% with a single %.  Names without
+ * a replacement are left as they are.  Gives the new text, and how many names were replaced.
+ */
+fn substitute_text(state: &ForthState, text: &[UnsignedByte]) -> (Vec<UnsignedByte>, usize) {
+    let mut result = Vec::new();
+    let mut substitutions = 0;
+    let mut rest = text;
+    while let Some(start) = rest.iter().position(|byte| *byte == b'%') {
+        result.extend_from_slice(&rest[..start]);
+        let end = match rest[start + 1..].iter().position(|byte| *byte == b'%') {
+            Some(end) => start + 1 + end,
+            None => {
+                rest = &rest[start..];
+                break
+            }
+        };
+
+        let name = &rest[start + 1..end];
+        if name.is_empty() {
+            result.push(b'%');
+        } else if let Some(replacement) = state.substitutions.get(&state.definitions.key(&String::from_utf8_lossy(name))) {
+            result.extend_from_slice(replacement);
+            substitutions += 1;
+        } else {
+            result.extend_from_slice(&rest[start..=end]);
+        }
+        rest = &rest[end + 1..];
+    }
+
+    result.extend_from_slice(rest);
+    (result, substitutions)
+}
+
+pub fn substitute(state: &mut ForthState) -> ForthResult {
+    let (destination, capacity) = pop_string(state)?;
+    let text = pop_string_bytes(state)?;
+
+    let (result, substitutions) = substitute_text(state, &text);
+    // what doesn't fit is cut off, and the count is negative
+    let length = result.len().min(capacity.get_bytes());
+    state.write_bytes(destination, &result[..length])?;
+
+    state.stack.push(destination);
+    state.stack.push(Bytes::bytes(length));
+    state.stack.push(if length < result.len() { -1 } else { substitutions as generic_numbers::Number });
+    Ok(())
+}
+
+// doubles every %, so that SUBSTITUTE leaves the text as it is
+pub fn unescape_percents(state: &mut ForthState) -> ForthResult {
+    let destination: memory::Address = state.stack.pop()?;
+    let text = pop_string_bytes(state)?;
 
+    let escaped = text.iter().flat_map(|byte| if *byte == b'%' { vec![b'%', b'%'] } else { vec![*byte] }).collect::<Vec<_>>();
+    state.write_bytes(destination, &escaped)?;
+    state.stack.push(destination);
+    state.stack.push(Bytes::bytes(escaped.len()));
     Ok(())
 }
 
@@ -135,7 +479,26 @@ pub fn get_operations() -> Vec<(&'static str, bool, super::Operation)> {
         ("CMOVE", false, cmove),
         ("CMOVE>", false, cmove_backwards),
         ("MOVE", false, move_noclobber),
+        ("FILL", false, fill),
         ("ACCEPT", false, accept),
-        ("COUNT", false, count)
+        ("COUNT", false, count),
+        ("SOURCE", false, source),
+        ("REFILL", false, refill),
+        ("PARSE", false, parse),
+        ("PARSE-NAME", false, parse_name),
+        ("SAVE-INPUT", false, save_input),
+        ("RESTORE-INPUT", false, restore_input),
+        ("S\"", true, s_quote),
+        ("S\\\"", true, s_backslash_quote),
+        ("C\"", true, c_quote),
+        ("ABORT\"", true, abort_quote),
+        ("/STRING", false, slash_string),
+        ("BLANK", false, blank),
+        ("COMPARE", false, compare),
+        ("SEARCH", false, search),
+        ("SLITERAL", true, sliteral),
+        ("REPLACES", false, replaces),
+        ("SUBSTITUTE", false, substitute),
+        ("UNESCAPE", false, unescape_percents)
     ]
 }
\ No newline at end of file
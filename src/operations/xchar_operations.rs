@@ -0,0 +1,225 @@
+use std::convert::TryFrom;
+
+use super::*;
+use crate::evaluate::Error;
+use crate::environment::generic_numbers::{Number, UnsignedByte};
+
+
+/**
+ * The number of bytes in a utf-8 character, from its first byte.  Bytes that can't start a character count as a character
+ * of their own, so that stepping through a string always moves forward.
+ */
+pub fn utf8_length(first: UnsignedByte) -> usize {
+    match first {
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf7 => 4,
+        _ => 1
+    }
+}
+
+fn is_continuation(byte: UnsignedByte) -> bool {
+    byte & 0xc0 == 0x80
+}
+
+fn to_char(xchar: Number) -> Result<char, Error> {
+    u32::try_from(xchar).ok().and_then(char::from_u32).ok_or(Error::InvalidNumber)
+}
+
+/**
+ * How many columns a character takes up on a terminal: none for control characters and combining marks, and two for the
+ * wide characters of east asian scripts and emoji.
+ */
+fn char_width(c: char) -> Number {
+    match c as u32 {
+        0x00..=0x1f | 0x7f..=0x9f | 0x300..=0x36f | 0x1ab0..=0x1aff | 0x200b..=0x200f | 0x20d0..=0x20ff | 0xfe20..=0xfe2f => 0,
+        0x1100..=0x115f | 0x2e80..=0x303e | 0x3040..=0xa4cf | 0xac00..=0xd7a3 | 0xf900..=0xfaff | 0xfe30..=0xfe4f |
+        0xff00..=0xff60 | 0xffe0..=0xffe6 | 0x1f300..=0x1f64f | 0x1f900..=0x1f9ff | 0x20000..=0x3fffd => 2,
+        _ => 1
+    }
+}
+
+// reads the character at an address, giving it along with its size, or just the byte if it isn't valid utf-8
+fn read_xchar(state: &ForthState, address: memory::Address) -> Result<(Number, usize), Error> {
+    let first = state.read::<UnsignedByte>(address)?;
+    let length = utf8_length(first);
+    let bytes = state.read_bytes(address, Bytes::bytes(length)).unwrap_or_else(|_| vec![first]);
+    Ok(match std::str::from_utf8(&bytes).ok().and_then(|s| s.chars().next()) {
+        Some(c) => (c as Number, length),
+        None => (first as Number, 1)
+    })
+}
+
+fn encode(xchar: Number) -> Result<Vec<UnsignedByte>, Error> {
+    Ok(to_char(xchar)?.to_string().into_bytes())
+}
+
+pub fn xc_size(state: &mut ForthState) -> ForthResult {
+    let xchar: Number = state.stack.pop()?;
+    let size = encode(xchar)?.len();
+    state.stack.push(Bytes::bytes(size));
+    Ok(())
+}
+
+pub fn x_size(state: &mut ForthState) -> ForthResult {
+    let length: Bytes = state.stack.pop()?;
+    let address: memory::Address = state.stack.pop()?;
+
+    let size = if length == Bytes::zero() {
+        0
+    } else {
+        utf8_length(state.read::<UnsignedByte>(address)?).min(length.get_bytes())
+    };
+    state.stack.push(Bytes::bytes(size));
+    Ok(())
+}
+
+pub fn xc_fetch_plus(state: &mut ForthState) -> ForthResult {
+    let address: memory::Address = state.stack.pop()?;
+    let (xchar, length) = read_xchar(state, address)?;
+    state.stack.push(address.plus(Bytes::bytes(length)));
+    state.stack.push(xchar);
+    Ok(())
+}
+
+pub fn xc_store_plus(state: &mut ForthState) -> ForthResult {
+    let address: memory::Address = state.stack.pop()?;
+    let bytes = encode(state.stack.pop()?)?;
+    state.write_bytes(address, &bytes)?;
+    state.stack.push(address.plus(Bytes::bytes(bytes.len())));
+    Ok(())
+}
+
+// stores a character only if there is room for it in the buffer
+pub fn xc_store_plus_query(state: &mut ForthState) -> ForthResult {
+    let length: Bytes = state.stack.pop()?;
+    let address: memory::Address = state.stack.pop()?;
+    let bytes = encode(state.stack.pop()?)?;
+
+    let fits = bytes.len() <= length.get_bytes();
+    let written = if fits {
+        state.write_bytes(address, &bytes)?;
+        Bytes::bytes(bytes.len())
+    } else {
+        Bytes::zero()
+    };
+    state.stack.push(address.plus(written));
+    state.stack.push(length - written);
+    state.stack.push(fits as Number);
+    Ok(())
+}
+
+pub fn xc_comma(state: &mut ForthState) -> ForthResult {
+    let bytes = encode(state.stack.pop()?)?;
+    let address = state.data_space.top();
+    state.data_space.expand(Bytes::bytes(bytes.len()).to_cells());
+    state.write_bytes(address, &bytes)
+}
+
+pub fn xchar_plus(state: &mut ForthState) -> ForthResult {
+    let address: memory::Address = state.stack.pop()?;
+    let length = utf8_length(state.read::<UnsignedByte>(address)?);
+    state.stack.push(address.plus(Bytes::bytes(length)));
+    Ok(())
+}
+
+pub fn xchar_minus(state: &mut ForthState) -> ForthResult {
+    let mut address: memory::Address = state.stack.pop()?;
+    // back up over continuation bytes to the start of the character, but never more than a whole character
+    for _ in 0..4 {
+        address.subtract(Bytes::one());
+        if !is_continuation(state.read::<UnsignedByte>(address)?) {
+            break
+        }
+    }
+    state.stack.push(address);
+    Ok(())
+}
+
+pub fn plus_x_slash_string(state: &mut ForthState) -> ForthResult {
+    let length: Bytes = state.stack.pop()?;
+    let address: memory::Address = state.stack.pop()?;
+
+    let size = if length == Bytes::zero() {
+        Bytes::zero()
+    } else {
+        Bytes::bytes(utf8_length(state.read::<UnsignedByte>(address)?).min(length.get_bytes()))
+    };
+    state.stack.push(address.plus(size));
+    state.stack.push(length - size);
+    Ok(())
+}
+
+pub fn x_backslash_string_minus(state: &mut ForthState) -> ForthResult {
+    let length: Bytes = state.stack.pop()?;
+    let address: memory::Address = state.stack.pop()?;
+
+    let bytes = state.read_bytes(address, length)?;
+    let start = bytes.iter().rposition(|byte| !is_continuation(*byte)).unwrap_or(0);
+    state.stack.push(address);
+    state.stack.push(Bytes::bytes(start));
+    Ok(())
+}
+
+// cuts off a character at the end of the string that is missing some of its bytes
+pub fn trailing_garbage(state: &mut ForthState) -> ForthResult {
+    let length: Bytes = state.stack.pop()?;
+    let address: memory::Address = state.stack.pop()?;
+
+    let bytes = state.read_bytes(address, length)?;
+    let complete = match bytes.iter().rposition(|byte| !is_continuation(*byte)) {
+        Some(start) if start + utf8_length(bytes[start]) > bytes.len() => start,
+        _ => bytes.len()
+    };
+    state.stack.push(address);
+    state.stack.push(Bytes::bytes(complete));
+    Ok(())
+}
+
+pub fn xemit(state: &mut ForthState) -> ForthResult {
+    let bytes = encode(state.stack.pop()?)?;
+    print_operations::write_output_bytes(state, &bytes);
+    Ok(())
+}
+
+pub fn xkey(state: &mut ForthState) -> ForthResult {
+    let c = state.input_stream.next_char()?;
+    state.stack.push(c as Number);
+    Ok(())
+}
+
+pub fn xc_width(state: &mut ForthState) -> ForthResult {
+    let c = to_char(state.stack.pop()?)?;
+    state.stack.push(char_width(c));
+    Ok(())
+}
+
+pub fn x_width(state: &mut ForthState) -> ForthResult {
+    let length: Bytes = state.stack.pop()?;
+    let address: memory::Address = state.stack.pop()?;
+
+    let bytes = state.read_bytes(address, length)?;
+    let width = String::from_utf8_lossy(&bytes).chars().map(char_width).sum::<Number>();
+    state.stack.push(width);
+    Ok(())
+}
+
+pub fn get_operations() -> Vec<(&'static str, bool, super::Operation)> {
+    vec![
+        ("XC-SIZE", false, xc_size),
+        ("X-SIZE", false, x_size),
+        ("XC@+", false, xc_fetch_plus),
+        ("XC!+", false, xc_store_plus),
+        ("XC!+?", false, xc_store_plus_query),
+        ("XC,", false, xc_comma),
+        ("XCHAR+", false, xchar_plus),
+        ("XCHAR-", false, xchar_minus),
+        ("+X/STRING", false, plus_x_slash_string),
+        ("X\\STRING-", false, x_backslash_string_minus),
+        ("-TRAILING-GARBAGE", false, trailing_garbage),
+        ("XEMIT", false, xemit),
+        ("XKEY", false, xkey),
+        ("XC-WIDTH", false, xc_width),
+        ("X-WIDTH", false, x_width),
+    ]
+}
@@ -0,0 +1,163 @@
+use crate::environment::{memory::{Address, MemorySegment}, units::{Bytes, Cells}, value::Value};
+use crate::evaluate::{definition::ExecutionToken, ForthState, ForthResult, Error};
+use crate::operations::{stack_operations, control_flow_operations};
+use super::peephole::{self, Instruction};
+
+/**
+ * Overrides the automatic inlining decision for a single definition, set by INLINE and NOINLINE.
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum InlineHint {
+    Always,
+    Never,
+}
+
+/**
+ * Compiles an execution token into the current definition, copying the body of small leaf definitions instead of calling them.
+ * This is used both for normal compilation and COMPILE,
+ */
+pub fn compile(state: &mut ForthState, execution_token: ExecutionToken) -> ForthResult {
+    if let ExecutionToken::Definition(address) = execution_token {
+        if let Some(body) = inlinable_body(state, address)? {
+            return inline(state, address, &body);
+        }
+    }
+
+    state.data_space.push(execution_token.value());
+    Ok(())
+}
+
+// the cells between the prologue and epilogue of the definition at address, if it should be inlined
+fn inlinable_body(state: &ForthState, address: Address) -> Result<Option<Vec<Value>>, Error> {
+    let hint = state.optimizer.inline_hint(address);
+    if hint == Some(InlineHint::Never) || (hint.is_none() && !state.config().automatic_inlining) {
+        return Ok(None);
+    }
+
+    // the definition currently being compiled doesn't have its length written yet
+    if state.definitions.most_recent_definition().execution_token == ExecutionToken::Definition(address) {
+        return Ok(None);
+    }
+
+    let length = match state.data_space.read::<Bytes>(address.minus_cell(Cells::one())) {
+        Ok(length) => length,
+        Err(_) => return Ok(None)
+    };
+    if hint.is_none() && length.get_bytes() > state.config().definition_copy_threshold {
+        return Ok(None);
+    }
+
+    // inline the definition as it was written, rather than whatever the peephole optimizer turned it into
+    let cells = match state.optimizer.original_definition(address) {
+        Some(cells) => cells.to_vec(),
+        None => (0..length.to_cells().get_cells()).map(|i| state.data_space.read::<Value>(address.plus_cell(Cells::cells(i)))).collect::<Result<Vec<_>, _>>()?
+    };
+
+    let n = cells.len();
+    let is_operation = |value: Value, operation: crate::operations::Operation| matches!(value, Value::ExecutionToken(ExecutionToken::LeafOperation(op)) if op as usize == operation as usize);
+    if n < 3 || !is_operation(cells[0], stack_operations::push_stack_frame) || !is_operation(cells[n - 2], stack_operations::pop_stack_frame) || !is_operation(cells[n - 1], control_flow_operations::control_flow_break) {
+        return Ok(None);
+    }
+
+    // branches can land anywhere in the body, or just after it, where the epilogue was
+    let body_start = address.plus_cell(Cells::one());
+    let body_end = address.plus_cell(Cells::cells(n - 2));
+    for sequence in peephole::decode(state, address, &cells)?.iter().filter(|sequence| sequence.start > 0 && sequence.start < n - 2) {
+        let is_leaf_instruction = match sequence.instruction {
+            Instruction::Number(_) | Instruction::AddConstant(_) => true,
+            Instruction::Builtin(name, _) => !peephole::RETURN_STACK_BUILTINS.contains(&name),
+            Instruction::Simple(xt) => matches!(xt, ExecutionToken::CompiledInstruction(_)),
+            Instruction::Control => match cells[sequence.start] {
+                Value::ExecutionToken(xt @ ExecutionToken::CompiledInstruction(_)) => state.compiled_instructions.branch_target(xt, address.plus_cell(Cells::cells(sequence.start)))
+                    .map(|target| !target.less_than(body_start) && !body_end.less_than(target))
+                    .unwrap_or(false),
+                // returning early can't be inlined
+                _ => false
+            },
+            Instruction::Opaque => false,
+        };
+
+        if !is_leaf_instruction {
+            return Ok(None);
+        }
+    }
+
+    Ok(Some(cells[1..n - 2].to_vec()))
+}
+
+fn inline(state: &mut ForthState, address: Address, body: &[Value]) -> ForthResult {
+    let body_start = address.plus_cell(Cells::one());
+    let destination = state.data_space.top();
+
+    for (i, value) in body.iter().enumerate() {
+        let value = match *value {
+            Value::ExecutionToken(xt @ ExecutionToken::CompiledInstruction(_)) => match state.compiled_instructions.branch_target(xt, body_start.plus_cell(Cells::cells(i))) {
+                // absolute branches have to be pointed at the copy
+                Some(target) => state.compiled_instructions.relocate(xt, destination.plus(target.offset_from(body_start))).value(),
+                None => xt.value()
+            },
+            value => value
+        };
+
+        state.data_space.push(value);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+fn calls(state: &ForthState, caller: &str, callee: &str) -> bool {
+    let address = |name: &str| match state.definitions.get_from_str(name).unwrap().execution_token {
+        ExecutionToken::Definition(address) => address,
+        _ => panic!("{} should be a definition", name)
+    };
+
+    let (caller, callee) = (address(caller), address(callee));
+    let length = state.data_space.read::<Bytes>(caller.minus_cell(Cells::one())).unwrap();
+    (0..length.to_cells().get_cells()).any(|i| matches!(state.data_space.read::<Value>(caller.plus_cell(Cells::cells(i))), Ok(Value::ExecutionToken(ExecutionToken::Definition(address))) if address == callee))
+}
+
+// keeps calls in tail position from turning into jumps, so they can be found by calls
+#[cfg(test)]
+fn forth<'a, 'i, 'o>() -> crate::evaluate::Forth<'a, 'i, 'o, crate::evaluate::kernels::DefaultKernel> {
+    crate::evaluate::Forth::new(crate::evaluate::config::ForthConfig { tail_call_elimination: false, ..Default::default() })
+}
+
+#[test]
+fn inline_leaf_test() {
+    let mut f = forth();
+    assert!(f.evaluate_string(": sq dup * ; : quad sq sq ; 3 quad").is_ok());
+    assert_eq!(f.state.stack.pop::<crate::environment::generic_numbers::Number>(), Ok(81));
+    assert!(!calls(&f.state, "QUAD", "SQ"));
+
+    // inlining sq into cube makes cube a leaf as well
+    assert!(f.evaluate_string(": cube dup sq * ; : nine 3 cube ;").is_ok());
+    assert!(!calls(&f.state, "NINE", "CUBE"));
+
+    // definitions that aren't leaves, or that use the return stack, are still called
+    assert!(f.evaluate_string(": greet .\" hi\" ; : greet-twice greet greet ; : welcome greet-twice ; : first 1 0 do i loop ;").is_ok());
+    assert!(calls(&f.state, "GREET-TWICE", "GREET"));
+    assert!(calls(&f.state, "WELCOME", "GREET-TWICE"));
+    assert!(calls(&f.state, "FIRST", "I"));
+}
+
+#[test]
+fn inline_branch_relocation_test() {
+    let mut f = forth();
+    assert!(f.evaluate_string(": abs2 dup 0< if negate then ; : inc-abs abs2 1 + ; -5 inc-abs 5 inc-abs").is_ok());
+    assert!(!calls(&f.state, "INC-ABS", "ABS2"));
+    assert_eq!(f.state.stack.to_vec().iter().map(|value| value.to_number()).collect::<Vec<_>>(), vec![6, 6]);
+
+    // too large to inline automatically, but forced with INLINE
+    assert!(f.evaluate_string(": sign 0< if -1 else 1 then ; inline : signs sign swap sign ; -3 4 signs").is_ok());
+    assert!(!calls(&f.state, "SIGNS", "SIGN"));
+    assert_eq!(f.state.stack.to_vec().iter().map(|value| value.to_number()).collect::<Vec<_>>(), vec![6, 6, 1, -1]);
+}
+
+#[test]
+fn no_inline_test() {
+    let mut f = forth();
+    assert!(f.evaluate_string(": one 1 ; noinline : two one one + ; two").is_ok());
+    assert!(calls(&f.state, "TWO", "ONE"));
+    assert_eq!(f.state.stack.pop::<crate::environment::generic_numbers::Number>(), Ok(2));
+}
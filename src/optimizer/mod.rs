@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+
+use crate::environment::{memory::Address, value::Value};
+use crate::evaluate::{self, ForthResult, ForthState};
+use crate::operations;
+
+mod inlining;
+mod peephole;
+mod tail_calls;
+
+pub use inlining::{compile, InlineHint};
+
+/**
+ * Optimization passes that run over a definition once it has finished compiling.  The passes never move cells around,
+ * since absolute branches, leave addresses, and DOES> all hold on to addresses inside of definitions.  Instead, a sequence
+ * of cells is replaced by a single instruction in its first cell, which skips over the rest.
+ */
+pub struct Optimizer {
+    // names of the builtin operations, keyed by their function pointers, so compiled cells can be recognized even if the word was redefined
+    builtin_names: HashMap<usize, &'static str>,
+    builtin_operations: HashMap<&'static str, operations::Operation>,
+    // the cells of each definition that was changed by the optimizer, before it was optimized
+    original_definitions: HashMap<Address, Vec<Value>>,
+    // definitions marked with INLINE or NOINLINE
+    inline_hints: HashMap<Address, InlineHint>,
+}
+
+impl Optimizer {
+    pub fn new() -> Self {
+        let operations = operations::get_operations();
+
+        // identical operations can end up sharing a function pointer, in which case the first name wins
+        let mut builtin_names = HashMap::new();
+        for (name, _, operation) in operations.iter() {
+            builtin_names.entry(*operation as usize).or_insert(*name);
+        }
+
+        Self {
+            builtin_names,
+            builtin_operations: operations.into_iter().map(|(name, _, operation)| (name, operation)).collect(),
+            original_definitions: HashMap::new(),
+            inline_hints: HashMap::new(),
+        }
+    }
+
+    pub fn builtin_name(&self, operation: operations::Operation) -> Option<&'static str> {
+        self.builtin_names.get(&(operation as usize)).copied()
+    }
+
+    pub fn builtin_operation(&self, name: &str) -> Option<operations::Operation> {
+        self.builtin_operations.get(name).copied()
+    }
+
+    pub fn inline_hint(&self, address: Address) -> Option<InlineHint> {
+        self.inline_hints.get(&address).copied()
+    }
+
+    pub fn set_inline_hint(&mut self, address: Address, hint: InlineHint) {
+        self.inline_hints.insert(address, hint);
+    }
+
+    pub fn original_definition(&self, address: Address) -> Option<&[Value]> {
+        self.original_definitions.get(&address).map(|cells| cells.as_slice())
+    }
+}
+
+/**
+ * Runs the enabled optimization passes over the most recent definition.  Called by ; after the epilogue and length have been written,
+ * but before the temporary definitions (locals) are cleared.
+ */
+pub fn optimize_definition(state: &mut ForthState) -> ForthResult {
+    let address = match state.definitions.most_recent_definition().execution_token {
+        evaluate::definition::ExecutionToken::Definition(address) => address,
+        _ => return Ok(())
+    };
+
+    let cells = state.data_space.top().offset_from(address).to_cells().get_cells();
+    let original = (0..cells).map(|i| state.read::<Value>(address.plus_cell(i.into()))).collect::<Result<Vec<_>, _>>()?;
+
+    let mut changed = false;
+    if state.config().peephole_optimizations {
+        let has_locals = state.definitions.has_temp();
+        changed |= peephole::optimize(state, address, &original, has_locals)?;
+    }
+    // runs after the peephole pass, which may have already removed the stack frame
+    if state.config().tail_call_elimination {
+        changed |= tail_calls::optimize(state, address, cells)?;
+    }
+
+    if changed {
+        state.optimizer.original_definitions.insert(address, original);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,268 @@
+use crate::environment::{generic_numbers::{Number, UnsignedByte}, memory::Address, units::{Bytes, Cells}, value::Value};
+use crate::evaluate::{definition::ExecutionToken, ForthState, ForthResult, Error};
+use crate::operations::{self, Operation};
+
+// builtins that move the instruction pointer, so they can't be executed from anywhere other than their own cell
+const CONTROL_FLOW_BUILTINS: &[&str] = &["BREAK", "EXIT"];
+// builtins that look at the return stack below what the definition itself pushed, and so need the stack frame to stay where it is
+pub(super) const RETURN_STACK_BUILTINS: &[&str] = &[">R", "R>", "R@", "2>R", "2R>", "2R@", "PUSH_FRAME", "POP_FRAME"];
+
+// sequences of two builtins that can be replaced with a single builtin
+const FUSED_PAIRS: &[(&str, &str, &str)] = &[
+    ("DUP", "+", "2*"),
+    ("SWAP", "DROP", "NIP"),
+    ("DROP", "DROP", "2DROP"),
+    ("OVER", "OVER", "2DUP"),
+];
+
+#[derive(Clone, Copy)]
+pub(super) enum Instruction {
+    Number(Number),
+    AddConstant(Number),
+    Builtin(&'static str, Operation),
+    // calls and compiled instructions that leave the instruction pointer alone
+    Simple(ExecutionToken),
+    // branches and returns
+    Control,
+    // branches, inline data, and anything else the optimizer doesn't understand
+    Opaque,
+}
+
+impl Instruction {
+    fn is_fusable(self) -> bool {
+        !matches!(self, Self::Control | Self::Opaque)
+    }
+
+    fn is_builtin(self, name: &str) -> bool {
+        matches!(self, Self::Builtin(builtin, _) if builtin == name)
+    }
+}
+
+/**
+ * An instruction, along with the cells it covers.  Only the first cell is ever rewritten.
+ */
+#[derive(Clone, Copy)]
+pub(super) struct Sequence {
+    pub(super) start: usize,
+    span: usize,
+    pub(super) instruction: Instruction,
+    changed: bool,
+}
+
+impl Sequence {
+    fn fuse(first: Sequence, last: Sequence, instruction: Instruction) -> Self {
+        Self { start: first.start, span: last.start + last.span - first.start, instruction, changed: true }
+    }
+}
+
+pub(super) fn decode(state: &ForthState, address: Address, cells: &[Value]) -> Result<Vec<Sequence>, Error> {
+    let mut sequences = Vec::new();
+    let mut i = 0;
+    while i < cells.len() {
+        let mut span = 1;
+        let instruction = match cells[i] {
+            Value::Number(n) | Value::ExecutionToken(ExecutionToken::Number(n)) => Instruction::Number(n),
+            Value::ExecutionToken(ExecutionToken::LeafOperation(operation)) => {
+                if operations::has_inline_string(operation) {
+                    // the length byte and characters follow the instruction
+                    let length: UnsignedByte = state.read(address.plus_cell(Cells::cells(i + 1)))?;
+                    span += Bytes::bytes(length as usize + 1).to_cells().get_cells();
+                    Instruction::Opaque
+                } else {
+                    match state.optimizer.builtin_name(operation) {
+                        Some(name) if CONTROL_FLOW_BUILTINS.contains(&name) => Instruction::Control,
+                        // DOES> reads the instruction pointer
+                        Some(name) if name != "DOES>" => Instruction::Builtin(name, operation),
+                        _ => Instruction::Opaque
+                    }
+                }
+            },
+            Value::ExecutionToken(xt @ ExecutionToken::CompiledInstruction(_)) => if state.compiled_instructions.is_branch(xt) {
+                Instruction::Control
+            } else {
+                Instruction::Simple(xt)
+            },
+            Value::ExecutionToken(xt @ ExecutionToken::Definition(_)) => Instruction::Simple(xt),
+        };
+
+        sequences.push(Sequence { start: i, span: span.min(cells.len() - i), instruction, changed: false });
+        i += span;
+    }
+
+    Ok(sequences)
+}
+
+fn fold(a: Number, b: Number, operation: &str) -> Option<Number> {
+    match operation {
+        "+" => a.checked_add(b),
+        "-" => a.checked_sub(b),
+        "*" => a.checked_mul(b),
+        "AND" => Some(a & b),
+        "OR" => Some(a | b),
+        _ => None
+    }
+}
+
+// try to replace the last few sequences with a single one, returning whether anything changed
+fn reduce(state: &ForthState, sequences: &mut Vec<Sequence>) -> bool {
+    let n = sequences.len();
+
+    // constant folding: a b op
+    if n >= 3 {
+        if let [a, b, c] = sequences[n - 3..] {
+            if let (Instruction::Number(x), Instruction::Number(y), Instruction::Builtin(name, _)) = (a.instruction, b.instruction, c.instruction) {
+                if let Some(result) = fold(x, y, name) {
+                    sequences.truncate(n - 3);
+                    sequences.push(Sequence::fuse(a, c, Instruction::Number(result)));
+                    return true;
+                }
+            }
+        }
+    }
+
+    if n >= 2 {
+        if let [a, b] = sequences[n - 2..] {
+            // adding a constant: n + and n -
+            if let Instruction::Number(x) = a.instruction {
+                let constant = if b.instruction.is_builtin("+") { Some(x) } else if b.instruction.is_builtin("-") { x.checked_neg() } else { None };
+                if let Some(constant) = constant {
+                    sequences.truncate(n - 2);
+                    sequences.push(Sequence::fuse(a, b, Instruction::AddConstant(constant)));
+                    return true;
+                }
+            }
+
+            for (first, second, fused) in FUSED_PAIRS {
+                if a.instruction.is_builtin(first) && b.instruction.is_builtin(second) {
+                    if let Some(operation) = state.optimizer.builtin_operation(fused) {
+                        sequences.truncate(n - 2);
+                        sequences.push(Sequence::fuse(a, b, Instruction::Builtin(fused, operation)));
+                        return true;
+                    }
+                }
+            }
+        }
+    }
+
+    false
+}
+
+// the stack frame is only needed by locals, and by code that digs under its own return address
+fn needs_stack_frame(sequences: &[Sequence], has_locals: bool) -> bool {
+    has_locals || sequences.len() < 4 || sequences[1..sequences.len() - 2].iter().any(|sequence| match sequence.instruction {
+        Instruction::Builtin(name, _) => RETURN_STACK_BUILTINS.contains(&name),
+        // closures compiled by control structures (loops, locals, strings) aren't understood well enough to be sure
+        Instruction::Opaque => true,
+        _ => false,
+    })
+}
+
+// drop the prologue and epilogue by folding the prologue into the next instruction, and returning directly from the epilogue
+fn remove_stack_frame(state: &ForthState, sequences: &mut Vec<Sequence>) -> bool {
+    let n = sequences.len();
+    if n < 4 || !sequences[0].instruction.is_builtin("PUSH_FRAME") || !sequences[n - 2].instruction.is_builtin("POP_FRAME") || !sequences[1].instruction.is_fusable() {
+        return false;
+    }
+
+    match state.optimizer.builtin_operation("BREAK") {
+        Some(operation) => sequences[n - 2] = Sequence { instruction: Instruction::Builtin("BREAK", operation), changed: true, ..sequences[n - 2] },
+        None => return false
+    }
+
+    let prologue = sequences.remove(0);
+    sequences[0] = Sequence::fuse(prologue, sequences[0], sequences[0].instruction);
+    true
+}
+
+fn encode(state: &mut ForthState, sequence: Sequence) -> ExecutionToken {
+    let skip = Cells::cells(sequence.span - 1);
+    let operation = match sequence.instruction {
+        Instruction::Number(n) if sequence.span == 1 => return ExecutionToken::Number(n),
+        Instruction::Builtin(_, operation) if sequence.span == 1 => return ExecutionToken::LeafOperation(operation),
+        Instruction::Simple(xt) if sequence.span == 1 => return xt,
+        Instruction::Number(n) => crate::compiled_instructions::FusedOperation::Push(n),
+        Instruction::AddConstant(n) => crate::compiled_instructions::FusedOperation::AddConstant(n),
+        Instruction::Builtin(_, operation) => crate::compiled_instructions::FusedOperation::Execute(ExecutionToken::LeafOperation(operation)),
+        Instruction::Simple(xt) => crate::compiled_instructions::FusedOperation::Execute(xt),
+        Instruction::Control | Instruction::Opaque => unreachable!("control flow and opaque instructions are never changed"),
+    };
+
+    state.compiled_instructions.compiler().fused(operation, skip)
+}
+
+/**
+ * Runs the peephole pass over the definition at address, whose cells are given.  Returns whether the definition was changed.
+ */
+pub fn optimize(state: &mut ForthState, address: Address, cells: &[Value], has_locals: bool) -> Result<bool, Error> {
+    let mut sequences = Vec::new();
+    for sequence in decode(state, address, cells)? {
+        sequences.push(sequence);
+        while reduce(state, &mut sequences) {}
+    }
+
+    let mut changed = sequences.iter().any(|sequence| sequence.changed);
+    if !needs_stack_frame(&sequences, has_locals) {
+        changed |= remove_stack_frame(state, &mut sequences);
+    }
+
+    for sequence in sequences.into_iter().filter(|sequence| sequence.changed) {
+        let xt = encode(state, sequence);
+        write_cell(state, address, sequence.start, xt)?;
+    }
+
+    Ok(changed)
+}
+
+fn write_cell(state: &mut ForthState, address: Address, index: usize, xt: ExecutionToken) -> ForthResult {
+    state.write(address.plus_cell(Cells::cells(index)), xt)
+}
+
+#[cfg(test)]
+fn evaluate_both_ways(source: &str) -> (Vec<Number>, Vec<Number>) {
+    use crate::evaluate::{config::ForthConfig, kernels::DefaultKernel, Forth};
+
+    let mut results = Vec::new();
+    for peephole_optimizations in [false, true] {
+        let mut f = Forth::<DefaultKernel>::new(ForthConfig { peephole_optimizations, ..Default::default() });
+        assert!(f.evaluate_string(source).is_ok());
+        results.push(f.state.stack.to_vec().iter().map(|value| value.to_number()).collect());
+    }
+
+    (results.remove(0), results.remove(0))
+}
+
+#[test]
+fn constant_folding_test() {
+    let mut f = crate::evaluate::Forth::<crate::evaluate::kernels::DefaultKernel>::new(Default::default());
+    assert!(f.evaluate_string(": f 2 3 + 4 * ; f").is_ok());
+    assert_eq!(f.state.stack.pop::<Number>(), Ok(20));
+
+    // the prologue and the whole expression collapse into the first cell, and the original is kept around for SEE
+    let address = match f.state.definitions.get_from_str("F").unwrap().execution_token {
+        ExecutionToken::Definition(address) => address,
+        _ => panic!("F should be a definition")
+    };
+    assert!(f.state.compiled_instructions.is_branch(f.state.read(address).unwrap()));
+    assert_eq!(f.state.optimizer.original_definition(address).map(|cells| cells.len()), Some(8));
+}
+
+#[test]
+fn same_results_test() {
+    let (unoptimized, optimized) = evaluate_both_ways(": f dup + swap drop 1 + 3 - ; 4 5 f");
+    assert_eq!(unoptimized, optimized);
+    assert_eq!(optimized, vec![8]);
+
+    // branches land in the middle of fused sequences, which still hold the original instructions
+    let (unoptimized, optimized) = evaluate_both_ways(": g if 2 3 + else 4 5 * then 1 + ; 1 g 0 g");
+    assert_eq!(unoptimized, optimized);
+    assert_eq!(optimized, vec![6, 21]);
+
+    let (unoptimized, optimized) = evaluate_both_ways(": h 0 swap 0 do i + loop ; 5 h : k { a b } a b - ; 1 3 k");
+    assert_eq!(unoptimized, optimized);
+}
+
+#[test]
+fn exit_without_frame_test() {
+    let (_, optimized) = evaluate_both_ways(": e 1 exit 2 ; e 3");
+    assert_eq!(optimized, vec![1, 3]);
+}
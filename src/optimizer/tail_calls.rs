@@ -0,0 +1,96 @@
+use crate::environment::{memory::Address, units::Cells, value::Value};
+use crate::evaluate::{definition::ExecutionToken, ForthState, Error};
+use crate::operations::{stack_operations, control_flow_operations};
+
+fn is_operation(value: Value, operation: crate::operations::Operation) -> bool {
+    matches!(value, Value::ExecutionToken(ExecutionToken::LeafOperation(op)) if op as usize == operation as usize)
+}
+
+/**
+ * Replaces a call that is directly followed by the epilogue with a jump, so that the callee returns straight to the caller's caller.
+ * If the peephole pass already removed the stack frame, the epilogue is just two breaks, and there is no frame to drop.
+ * Returns whether the definition was changed.
+ */
+pub fn optimize(state: &mut ForthState, address: Address, cells: usize) -> Result<bool, Error> {
+    if cells < 4 {
+        return Ok(false);
+    }
+
+    let cell = |i: usize| state.read::<Value>(address.plus_cell(Cells::cells(i)));
+    let (call, epilogue, last) = (cell(cells - 3)?, cell(cells - 2)?, cell(cells - 1)?);
+    if !is_operation(last, control_flow_operations::control_flow_break) {
+        return Ok(false);
+    }
+
+    let target = match call {
+        Value::ExecutionToken(ExecutionToken::Definition(target)) => target,
+        _ => return Ok(false)
+    };
+
+    // local stubs live inside the definition, and read the frame of the definition that called them, so it has to stay around
+    let end = address.plus_cell(Cells::cells(cells));
+    if address.less_than(target) && target.less_than(end) {
+        return Ok(false);
+    }
+
+    let jump = if is_operation(epilogue, stack_operations::pop_stack_frame) {
+        state.compiled_instructions.compiler().tail_call(target)
+    } else if is_operation(epilogue, control_flow_operations::control_flow_break) {
+        state.compiled_instructions.compiler().tail_jump(target)
+    } else {
+        return Ok(false);
+    };
+
+    state.write(address.plus_cell(Cells::cells(cells - 3)), jump)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod depth_kernel {
+    use crate::evaluate::{kernels::Kernel, ForthState, ForthResult};
+
+    // records the deepest the return stack gets
+    pub struct DepthKernel(pub usize);
+    impl Kernel for DepthKernel {
+        type NextKernel = Self;
+        fn new(_: &mut ForthState) -> Self { Self(0) }
+        fn get_next_kernel(&mut self) -> &mut Self::NextKernel { self }
+        fn evaluate(&mut self, state: &mut ForthState) -> ForthResult {
+            self.0 = self.0.max(state.return_stack.len().get_cells());
+            Ok(())
+        }
+        fn evaluate_chain(&mut self, state: &mut ForthState) -> ForthResult { self.evaluate(state) }
+    }
+}
+
+#[cfg(test)]
+fn deepest_return_stack(source: &str, tail_call_elimination: bool) -> (Vec<crate::environment::generic_numbers::Number>, usize) {
+    let config = crate::evaluate::config::ForthConfig { tail_call_elimination, ..Default::default() };
+    let mut f = crate::evaluate::Forth::<depth_kernel::DepthKernel>::new(config);
+    assert!(f.evaluate_string(source).is_ok());
+    (f.state.stack.to_vec().iter().map(|value| value.to_number()).collect(), f.kernel.0)
+}
+
+#[test]
+fn deep_recursion_test() {
+    let source = ": countdown dup if 1 - countdown then ; 10000 countdown";
+    let (stack, depth) = deepest_return_stack(source, true);
+    assert_eq!(stack, vec![0]);
+    assert!(depth <= 2);
+
+    let (stack, depth) = deepest_return_stack(source, false);
+    assert_eq!(stack, vec![0]);
+    assert!(depth >= 10000);
+}
+
+#[test]
+fn tail_call_with_frame_test() {
+    // the locals keep the stack frame around, so the tail call has to drop it before jumping
+    let source = ": sum-down { n acc } n 0= if acc else acc n + n 1 - sum-down then ; 0 1000 sum-down";
+    let (stack, depth) = deepest_return_stack(source, true);
+    assert_eq!(stack, vec![500500]);
+    assert!(depth <= 4);
+
+    let (unoptimized, _) = deepest_return_stack(source, false);
+    assert_eq!(unoptimized, stack);
+}
@@ -1,4 +1,4 @@
-use forth::{Error, Forth, Number, output_stream, stack};
+use forth::{config, kernels, Error, Forth, Number, memory, output_stream, stack};
 
 
 pub fn stack_to_vec(stack: &stack::Stack) -> Vec<Number> {
@@ -530,3 +530,390 @@ fn materials_program_test() {
     assert!(f.evaluate_string("cement 10 foot pile 10 foot 3 inch pile dry-sand 10 foot pile").is_ok());
     assert_eq!("= 138 tons of cement= 151 tons of cement= 81 tons of dry sand", f.state.output_stream.consume());
 }
+
+#[test]
+fn fill_and_move_test() {
+    let mut f = Forth::default();
+    assert!(f.evaluate_string("here 4 cells allot constant buffer  buffer 4 cells 7 fill").is_ok());
+    assert!(f.evaluate_string("buffer c@ buffer 31 + c@ buffer @").is_ok());
+    assert_eq!(vec![7, 7, 0x0707070707070707], stack_to_vec(&f.state.stack));
+
+    // cmove smears a byte forward over an overlapping destination, while move copies as if through a buffer
+    assert!(f.evaluate_string("drop drop drop  1 buffer c!  2 buffer 1 + c!  buffer buffer 1 + 4 cmove  buffer 4 + c@").is_ok());
+    assert_eq!(vec![1], stack_to_vec(&f.state.stack));
+    assert!(f.evaluate_string("drop  1 buffer c!  2 buffer 1 + c!  buffer buffer 1 + 4 move  buffer 2 + c@").is_ok());
+    assert_eq!(vec![2], stack_to_vec(&f.state.stack));
+
+    // copies of less than a cell, at the same offset into their cells, don't carry any whole cells
+    assert!(f.evaluate_string("drop  3 buffer 1 + c!  4 buffer 2 + c!  buffer 1 + buffer 9 + 2 move  buffer 2 + buffer 18 + 1 cmove  buffer 9 + c@ buffer 10 + c@ buffer 18 + c@").is_ok());
+    assert_eq!(vec![3, 4, 4], stack_to_vec(&f.state.stack));
+
+    // negative counts are huge lengths, which can't fit anywhere
+    for source in ["buffer buffer 1 + -1 cmove", "buffer 1 + buffer -1 cmove>", "buffer 1 + buffer -1 move", "buffer -1 0 fill",
+            "buffer -1 blank", "buffer 5 buffer -1 compare"].iter() {
+        assert_eq!(Err(Error::InvalidAddress), f.evaluate_string(source));
+    }
+}
+
+#[test]
+fn execution_token_bytes_test() {
+    let mut f = Forth::default();
+    assert!(f.evaluate_string("here 2 cells allot constant buffer  ' dup buffer !").is_ok());
+
+    // the bytes of an execution token can be read, and a moved cell can still be executed
+    assert!(f.evaluate_string("buffer c@ buffer @ 255 and =  buffer buffer cell+ 1 cells move  3 buffer cell+ @ execute").is_ok());
+    assert_eq!(vec![1, 3, 3], stack_to_vec(&f.state.stack));
+
+    // writing over any byte of it leaves a plain number
+    assert_eq!(Err(Error::InvalidExecutionToken), f.evaluate_string("0 buffer c! buffer @ execute"));
+}
+
+#[test]
+fn protect_and_unmap_test() {
+    let mut f = Forth::default();
+    let page = config::ForthConfig::default().anonymous_mappings_addr as Number;
+    let next_page = page + memory::PAGE_SIZE as Number;
+
+    assert!(f.evaluate_string("2 map constant pages  5 pages !  pages mapping?").is_ok());
+    assert_eq!(stack_to_vec(&f.state.stack), vec![page, 3]);
+
+    // protecting the second page splits the mapping in two
+    assert!(f.evaluate_string("drop drop  pages 4096 + 1 1 protect  pages 4096 + mapping?  pages 8 + mapping?  pages 4096 + @").is_ok());
+    assert_eq!(stack_to_vec(&f.state.stack), vec![next_page, 1, page, 3, 0]);
+    assert!(f.evaluate_string("drop drop drop drop drop").is_ok());
+    assert_eq!(Err(Error::InsufficientPermissions), f.evaluate_string("6 pages 4096 + !"));
+    assert_eq!(Err(Error::InsufficientPermissions), f.evaluate_string("pages 4000 + 200 0 fill"));
+
+    // pages have to be part of a single anonymous mapping
+    assert_eq!(Err(Error::InvalidAddress), f.evaluate_string("pages 8 + 1 3 protect"));
+    assert_eq!(Err(Error::InvalidAddress), f.evaluate_string("pages 3 3 protect"));
+    assert_eq!(Err(Error::InvalidAddress), f.evaluate_string("here 1 3 protect"));
+    assert_eq!(stack_to_vec(&f.state.stack), vec![]);
+
+    assert!(f.evaluate_string("pages 1 unmap  pages mapping?  pages 4096 + mapping?").is_ok());
+    assert_eq!(stack_to_vec(&f.state.stack), vec![0, 0, next_page, 1]);
+    assert_eq!(Err(Error::InvalidAddress), f.evaluate_string("pages @"));
+    assert_eq!(Err(Error::InvalidAddress), f.evaluate_string("pages 4096 + 1 unmap  pages 4096 + @"));
+}
+
+#[test]
+fn execute_permission_test() {
+    let mut f = Forth::default();
+    assert!(f.evaluate_string(": sq dup * ;  1 map constant page  ' sq page 5 cells move").is_ok());
+
+    // the copied code can only run while the page is executable
+    assert!(f.evaluate_string("page 1 5 protect  3 page body> execute").is_ok());
+    assert_eq!(stack_to_vec(&f.state.stack), vec![9]);
+    assert_eq!(Err(Error::InsufficientPermissions), f.evaluate_string("page 1 3 protect  page body> execute"));
+}
+
+#[test]
+fn stack_overflow_test() {
+    // the guard page just past the deepest the stack can go isn't mapped
+    let guard = format!("drop {} @", config::ForthConfig::default().stack_addr + 0x1000);
+    let mut f = Forth::<kernels::DefaultKernel>::new(config::ForthConfig { stack_depth: 4, ..Default::default() });
+    assert!(f.evaluate_string("1 2 3 4").is_ok());
+    assert_eq!(Err(Error::StackOverflow), f.evaluate_string("5"));
+    assert_eq!(vec![1, 2, 3, 4], stack_to_vec(&f.state.stack));
+    assert_eq!(Err(Error::InvalidAddress), f.evaluate_string(&guard));
+}
+
+#[test]
+fn return_stack_overflow_test() {
+    let mut f = Forth::<kernels::DefaultKernel>::new(config::ForthConfig { return_stack_depth: 64, ..Default::default() });
+    assert!(f.evaluate_string(": deep dup if 1 - deep 1 + then ; 10 deep").is_ok());
+    assert_eq!(vec![10], stack_to_vec(&f.state.stack));
+    assert_eq!(Err(Error::ReturnStackOverflow), f.evaluate_string("1000 deep"));
+}
+
+#[test]
+fn large_allocation_test() {
+    let mut f = Forth::<kernels::DefaultKernel>::new(config::ForthConfig { heap_diagnostics: true, ..Default::default() });
+    assert!(f.evaluate_string("4194304 allocate drop constant buffer  buffer 4194288 + 16 7 fill  5 buffer 2097152 + !").is_ok());
+    assert!(f.evaluate_string("buffer 4194303 + c@  buffer 2097152 + @").is_ok());
+    assert_eq!(stack_to_vec(&f.state.stack), vec![7, 5]);
+
+    assert!(f.evaluate_string("drop drop  buffer 8388608 resize drop constant bigger  bigger 4194303 + c@  bigger 8388600 + @").is_ok());
+    assert_eq!(stack_to_vec(&f.state.stack), vec![7, 0]);
+    assert_eq!(f.evaluate_string("buffer c@"), Err(Error::UseAfterFree));
+    assert!(f.evaluate_string("drop drop  bigger free").is_ok());
+    assert_eq!(stack_to_vec(&f.state.stack), vec![0]);
+    assert!(f.state.heap.outstanding_allocations().is_empty());
+
+    // sizes that can't be met give a failed ior, whether they are huge or negative
+    assert!(f.evaluate_string("drop  100000000000 allocate  -1 allocate").is_ok());
+    assert_eq!(stack_to_vec(&f.state.stack), vec![0, -1, 0, -1]);
+}
+
+// a register file, where reading the second register counts how many times the first has been written
+struct Registers {
+    base: forth::memory::Address,
+    values: [forth::Number; 2],
+}
+
+impl forth::memory::MemorySegment for Registers {
+    fn get_base(&self) -> forth::memory::Address {
+        self.base
+    }
+
+    fn get_end(&self) -> forth::memory::Address {
+        self.base.plus_cell(forth::units::Cells::cells(self.values.len()))
+    }
+
+    fn write_value(&mut self, address: forth::memory::Address, value: forth::Value) -> forth::ForthResult {
+        let index = self.cell_offset(address)?.get_cells();
+        self.values[index] = value.to_number();
+        if index == 0 {
+            self.values[1] += 1;
+        }
+        Ok(())
+    }
+
+    fn read_value(&self, address: forth::memory::Address) -> Result<forth::Value, Error> {
+        self.cell_offset(address).map(|index| forth::Value::Number(self.values[index.get_cells()]))
+    }
+
+    fn write_values(&mut self, address: forth::memory::Address, values: &[forth::Value]) -> forth::ForthResult {
+        values.iter().enumerate().try_for_each(|(i, value)| self.write_value(address.plus_cell(forth::units::Cells::cells(i)), *value))
+    }
+
+    fn read_values(&self, address: forth::memory::Address, len: forth::units::Cells) -> Result<Vec<forth::Value>, Error> {
+        (0..len.get_cells()).map(|i| self.read_value(address.plus_cell(forth::units::Cells::cells(i)))).collect()
+    }
+}
+
+#[test]
+fn host_segment_test() {
+    let base = forth::memory::Address::from_raw(forth::units::Bytes::bytes(0x10000000));
+    let mut f = Forth::<kernels::DefaultKernel>::new(config::ForthConfig::default());
+    let permissions = forth::memory::MemoryPermissions::readwrite();
+    assert_eq!(f.state.register_segment(Box::new(Registers { base, values: [0, 0] }), permissions, "registers"), Ok(base));
+    assert!(f.state.memory_map().get_entries().iter().any(|mapping| mapping.name == Some("registers") && mapping.base == base));
+
+    // the memory words all work on it
+    assert!(f.evaluate_string("268435456 constant regs  5 regs !  regs 8 + @  regs c@  7 regs c!  regs @  regs 8 + @").is_ok());
+    assert_eq!(stack_to_vec(&f.state.stack), vec![1, 5, 7, 2]);
+    assert_eq!(f.evaluate_string("regs 16 + @"), Err(Error::InvalidAddress));
+
+    // it can't overlap anything, and goes away when the host takes it back
+    let overlapping = Registers { base: base.plus_cell(forth::units::Cells::one()), values: [0, 0] };
+    assert_eq!(f.state.register_segment(Box::new(overlapping), permissions, "overlapping").err(), Some(Error::InvalidAddress));
+    assert!(f.state.unregister_segment(base).is_ok());
+    assert_eq!(f.evaluate_string("regs @"), Err(Error::InvalidAddress));
+}
+
+#[test]
+fn io_ports_test() {
+    use std::{cell::RefCell, rc::Rc};
+
+    // a device with a data port, which takes in numbers to add up, and a status port holding the total, which resets when it's read
+    let total = Rc::new(RefCell::new(0));
+    let (reader, writer) = (total.clone(), total.clone());
+    let base = forth::memory::Address::from_raw(forth::units::Bytes::bytes(0x20000000));
+    let ports = forth::io_ports::IoPorts::new(base, forth::units::Cells::cells(2))
+        .on_read(move |port| if port.get_cells() == 1 { reader.replace(0) } else { 0 })
+        .on_write(move |port, value| if port.get_cells() == 0 { *writer.borrow_mut() += value });
+
+    let mut f = Forth::<kernels::DefaultKernel>::new(config::ForthConfig::default());
+    assert!(f.state.register_segment(Box::new(ports), forth::memory::MemoryPermissions::readwrite(), "device").is_ok());
+    assert!(f.evaluate_string("536870912 constant data  data 8 + constant status  : send 0 do i data ! loop ;").is_ok());
+    assert!(f.evaluate_string("5 send").is_ok());
+    assert_eq!(*total.borrow(), 10);
+
+    assert!(f.evaluate_string("status @  status @  3 send  data @").is_ok());
+    assert_eq!(stack_to_vec(&f.state.stack), vec![10, 0, 0]);
+    assert_eq!(*total.borrow(), 3);
+    assert_eq!(f.evaluate_string("data 16 + @"), Err(Error::InvalidAddress));
+}
+
+#[test]
+fn number_base_test() {
+    let mut f = Forth::<kernels::DefaultKernel>::new(config::ForthConfig::default()).with_output_stream(output_stream::BufferedOutputStream::new());
+    assert!(f.evaluate_string("hex ff -1a base @ decimal 10  2 base ! 101 decimal").is_ok());
+    assert_eq!(stack_to_vec(&f.state.stack), vec![255, -26, 16, 10, 5]);
+
+    // the base carries over to later input, and printing uses it too
+    assert!(f.evaluate_string("hex").is_ok());
+    assert!(f.evaluate_string("ff . -1f . 20 u. decimal 255 .").is_ok());
+    assert_eq!(f.state.output_stream.consume(), "FF -1F 20 255 ");
+    assert_eq!(f.evaluate_string("1 base !"), Err(Error::InvalidNumber));
+    assert_eq!(f.evaluate_string("base @"), Ok(()));
+    assert_eq!(stack_to_vec(&f.state.stack).last(), Some(&10));
+
+    // words are found in the dictionary before they are read as numbers, even when spelled with the digits of the base
+    let mut f = Forth::default();
+    assert!(f.evaluate_string(": add + ;  hex  : bead 10 ;  1 2 add bead  c0de decimal").is_ok());
+    assert_eq!(stack_to_vec(&f.state.stack), vec![3, 16, 0xc0de]);
+}
+
+#[test]
+fn state_registers_test() {
+    // HERE is the fourth register
+    let here_register = config::ForthConfig::default().internal_state_memory_addr + 24;
+    let read_here = format!("here {} @ =", here_register);
+    let write_here = format!("0 {} !", here_register);
+
+    let mut f = Forth::<kernels::DefaultKernel>::new(config::ForthConfig::default());
+    // >in is read by @, once the @ itself has been consumed
+    assert!(f.evaluate_string("1 2 3 depth  source-id  >in @").is_ok());
+    assert_eq!(stack_to_vec(&f.state.stack), vec![1, 2, 3, 3, 0, 29]);
+
+    // >in can skip over input, and stops at the end of the line
+    assert!(f.evaluate_string("drop drop drop drop drop drop").is_ok());
+    assert!(f.evaluate_string("12 >in ! 1 2 3 4").is_ok());
+    assert_eq!(stack_to_vec(&f.state.stack), vec![3, 4]);
+    assert!(f.evaluate_string("1000 >in ! 5").is_ok());
+    assert_eq!(stack_to_vec(&f.state.stack), vec![3, 4]);
+
+    assert!(f.evaluate_string("drop drop").is_ok());
+    assert!(f.evaluate_string(&read_here).is_ok());
+    assert_eq!(stack_to_vec(&f.state.stack), vec![1]);
+    assert_eq!(f.evaluate_string(&write_here), Err(Error::InsufficientPermissions));
+    assert!(f.state.internal_state_memory().registers().iter().map(|register| register.name).eq(["STATE", "BASE", ">IN", "HERE", "SOURCE-ID", "DEPTH", "RDEPTH", "FRAME"]));
+}
+
+#[test]
+fn input_source_test() {
+    let lines = "source nip  \\ source nip\nparse-name  hello  nip  char ) parse (comment) nip\n7 ( spans\nlines ) 8";
+    let rewind = "again";
+    let evaluated = "char | parse source-id source nip| evaluate source-id";
+    let saved = "save-input drop drop drop drop 0 restore-input";
+
+    let mut f = Forth::<kernels::DefaultKernel>::new(config::ForthConfig::default());
+    // the comment ends at the end of the line, and parsed text stays in the input buffer
+    assert!(f.evaluate_string(lines).is_ok());
+    assert_eq!(stack_to_vec(&f.state.stack), vec![24, 5, 8, 7, 8]);
+
+    // >in can go back, to parse the line again
+    assert!(f.evaluate_string("drop drop drop drop drop here 0 , constant n  : again n @ 1+ dup n ! 3 < if 0 >in ! then ;").is_ok());
+    assert!(f.evaluate_string(rewind).is_ok());
+    assert!(f.evaluate_string("n @").is_ok());
+    assert_eq!(stack_to_vec(&f.state.stack), vec![3]);
+
+    // evaluate has its own source, and the outer line picks up where it left off
+    assert!(f.evaluate_string(evaluated).is_ok());
+    assert_eq!(stack_to_vec(&f.state.stack), vec![3, -1, 20, 0]);
+
+    // there is nothing to refill from in an evaluated string, and the input can't be restored from a mangled save
+    assert!(f.evaluate_string("drop drop drop drop char | parse refill| evaluate").is_ok());
+    assert!(f.evaluate_string(saved).is_ok());
+    assert_eq!(stack_to_vec(&f.state.stack), vec![0, 1]);
+}
+
+#[test]
+fn case_sensitivity_test() {
+    let mut f = Forth::<kernels::DefaultKernel>::new(config::ForthConfig::default());
+    // names are found whatever their case, but keep their spelling
+    assert!(f.evaluate_string(": Square Dup * ; 3 SQUARE 4 square").is_ok());
+    assert_eq!(stack_to_vec(&f.state.stack), vec![9, 16]);
+    let square = f.state.definitions.get_from_str("sQuArE").unwrap().execution_token;
+    assert_eq!(f.state.definitions.debug_only_get_name(square), Some("Square".to_string()));
+
+    let mut f = Forth::<kernels::DefaultKernel>::new(config::ForthConfig { case_sensitivity: config::CaseSensitivity::Sensitive, ..Default::default() });
+    assert!(f.evaluate_string(": Square DUP * ; 3 Square").is_ok());
+    assert_eq!(stack_to_vec(&f.state.stack), vec![9]);
+    assert_eq!(f.evaluate_string("3 square"), Err(Error::UnknownWord("square".to_string())));
+    assert_eq!(f.evaluate_string("dup"), Err(Error::UnknownWord("dup".to_string())));
+}
+
+#[test]
+fn string_literals_test() {
+    let mut f = Forth::default().with_output_stream(output_stream::BufferedOutputStream::new());
+    // interpreted strings go in transient buffers, and the last two stay around
+    assert!(f.evaluate_string("s\" hello\" s\"  world\" type type  s\\\" tab\\there\\q\\x21\" type").is_ok());
+    assert_eq!(f.state.output_stream.consume(), " worldhellotab\there\"!");
+
+    // compiled strings are part of the definition, and the optimizer skips over them
+    assert!(f.evaluate_string(": greet s\" hi\" type c\" there\" count type 1 2 + ; greet greet + .").is_ok());
+    assert_eq!(f.state.output_stream.consume(), "hitherehithere6 ");
+    assert!(f.evaluate_string("c\" abc\" c@").is_ok());
+    assert_eq!(stack_to_vec(&f.state.stack), vec![3]);
+
+    assert!(f.evaluate_string(": check abort\" out of range\" 7 ; 0 check").is_ok());
+    assert_eq!(stack_to_vec(&f.state.stack), vec![3, 7]);
+    assert_eq!(f.evaluate_string("1 check"), Err(Error::Abort("out of range".to_string())));
+    assert_eq!(f.evaluate_string("s\\\" bad\\y\""), Err(Error::InvalidWord));
+}
+
+#[test]
+fn string_word_set_test() {
+    let mut f = Forth::default().with_output_stream(output_stream::BufferedOutputStream::new());
+    assert!(f.evaluate_string("s\" abc\" s\" abd\" compare  s\" abc\" s\" ab\" compare  s\" abc\" s\" abc\" compare").is_ok());
+    assert_eq!(stack_to_vec(&f.state.stack), vec![-1, 1, 0]);
+
+    assert!(f.evaluate_string("drop drop drop s\" hello world\" s\" wor\" search . type  s\" hello\" s\" xyz\" search . type").is_ok());
+    assert_eq!(f.state.output_stream.consume(), "1 world0 hello");
+    assert!(f.evaluate_string("s\" hello\" 2 /string type  s\" 12345\" 2dup blank type").is_ok());
+    assert_eq!(f.state.output_stream.consume(), "llo     ");
+    // the length wraps around rather than overflowing
+    assert!(f.evaluate_string("s\" abc\" -9223372036854775807 1 - /string nip").is_ok());
+    assert_eq!(stack_to_vec(&f.state.stack), vec![i64::MIN + 3]);
+    assert!(f.evaluate_string("drop").is_ok());
+
+    assert!(f.evaluate_string(": name [ s\" forth\" ] sliteral ; name type").is_ok());
+    assert_eq!(f.state.output_stream.consume(), "forth");
+
+    // names are replaced, %% becomes %, and unknown names are left alone
+    assert!(f.evaluate_string("64 allocate drop constant buf  name s\" lang\" replaces  s\" %LANG% is 100%% %fun%\" buf 64 substitute . type").is_ok());
+    assert_eq!(f.state.output_stream.consume(), "1 forth is 100% %fun%");
+    assert!(f.evaluate_string("s\" %lang%\" buf 3 substitute . type  s\" 5%\" buf unescape type").is_ok());
+    assert_eq!(f.state.output_stream.consume(), "-1 for5%%");
+}
+
+#[test]
+fn xchar_test() {
+    let mut f = Forth::default().with_output_stream(output_stream::BufferedOutputStream::new());
+    // strings keep their utf-8 through memory, and EMIT can put a character back together a byte at a time
+    assert!(f.evaluate_string(": greet .\" héllo 世界\" ; greet  s\" ñ\" type  s\" é\" drop dup c@ emit 1+ c@ emit  char 界 xemit").is_ok());
+    assert_eq!(f.state.output_stream.consume(), "héllo 世界ñé界");
+
+    assert!(f.evaluate_string("s\" a世b\" 2constant str  str x-width  str drop 1+ xc@+ nip  str drop 1+ xchar+ c@  str 1 /string x-size").is_ok());
+    assert_eq!(stack_to_vec(&f.state.stack), vec![4, 0x4e16, 98, 3]);
+
+    // storing a character gives back the address just past it
+    assert!(f.evaluate_string("drop drop drop drop 16 allocate drop constant buf  0x1f600 buf xc!+ buf -  0x1f600 xc-size  buf 4 + xchar- buf -").is_ok());
+    assert_eq!(stack_to_vec(&f.state.stack), vec![4, 4, 0]);
+    assert!(f.evaluate_string("drop drop drop buf 3 -trailing-garbage nip  buf 4 x\\string- nip  buf 4 +x/string nip  buf xc@+ nip").is_ok());
+    assert_eq!(stack_to_vec(&f.state.stack), vec![0, 0, 0, 0x1f600]);
+
+    // evaluated strings are read as utf-8 too
+    assert!(f.evaluate_string("drop drop drop drop  s\" char é source type\" evaluate").is_ok());
+    assert_eq!(stack_to_vec(&f.state.stack), vec![0xe9]);
+    assert_eq!(f.state.output_stream.consume(), "char é source type");
+}
+
+#[test]
+fn bye_test() {
+    let mut f = Forth::default();
+    // leaving stops evaluation where it is, giving the host the exit code
+    assert_eq!(f.evaluate_string("1 bye 2"), Err(Error::Bye(0)));
+    assert_eq!(stack_to_vec(&f.state.stack), vec![1]);
+    assert_eq!(f.evaluate_string(": fail 3 (bye) ; 4 fail"), Err(Error::Bye(3)));
+    assert_eq!(stack_to_vec(&f.state.stack), vec![1, 4]);
+}
+
+#[test]
+fn pad_test() {
+    let mut f = Forth::default();
+    // interpreted strings have buffers of their own, so they leave the pad alone
+    assert!(f.evaluate_string("5 pad c!  s\" a\" 2drop s\" b\" 2drop s\" c\" 2drop  pad c@  s\" /pad\" environment? drop pad + 1 - c@").is_ok());
+    assert_eq!(stack_to_vec(&f.state.stack), vec![5, 0]);
+}
+
+#[test]
+fn environment_query_test() {
+    let mut f = Forth::<kernels::DefaultKernel>::new(config::ForthConfig { stack_depth: 100, ..Default::default() });
+    assert!(f.evaluate_string("s\" stack-cells\" environment?  s\" /counted-string\" environment?  s\" no-such-query\" environment?").is_ok());
+    assert_eq!(stack_to_vec(&f.state.stack), vec![100, 1, 255, 1, 0]);
+
+    // double numbers answer with two cells, the high cell on top
+    assert!(f.evaluate_string("drop drop drop drop drop  s\" max-d\" environment?  s\" floating\" environment?").is_ok());
+    assert_eq!(stack_to_vec(&f.state.stack), vec![-1, Number::MAX, 1, 0, 1]);
+    assert!(f.evaluate_string("drop drop drop drop drop  s\" /pad\" environment?  s\" /hold\" environment?").is_ok());
+    assert_eq!(stack_to_vec(&f.state.stack), vec![1024, 1, 0]);
+
+    // hosts can answer queries of their own
+    f.state.set_environment("X:DEVICE", 42 as Number);
+    assert!(f.evaluate_string("drop drop drop  s\" x:device\" environment?").is_ok());
+    assert_eq!(stack_to_vec(&f.state.stack), vec![42, 1]);
+}